@@ -0,0 +1,490 @@
+//! Streaming FASTQ reading and writing.
+//!
+//! `Reader::next_record` hands out records borrowing the reader's buffer
+//! so nothing is copied per record; `Reader::records` yields owned
+//! records for convenience. `open` detects gzip, bzip2 and zstd input by
+//! the magic bytes and `create` compresses by the file extension.
+
+use bzip2::{bufread::MultiBzDecoder, write::BzEncoder};
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// --------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression from the start of the data
+    pub fn from_magic(bytes: &[u8]) -> Compression {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if bytes.starts_with(BZIP2_MAGIC) {
+            Compression::Bzip2
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Chooses the compression from the extension, e.g., "foo.fq.gz"
+    pub fn from_path(path: &Path) -> Compression {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("bz2") => Compression::Bzip2,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+// --------------------------------------------------
+#[derive(Debug)]
+pub enum FastqError {
+    Io(io::Error),
+    Format {
+        name: Option<String>,
+        line: usize,
+        msg: String,
+    },
+}
+
+impl fmt::Display for FastqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FastqError::Io(e) => write!(f, "{}", e),
+            FastqError::Format {
+                name: Some(name),
+                line,
+                msg,
+            } => write!(f, "{}: line {}: {}", name, line, msg),
+            FastqError::Format {
+                name: None,
+                line,
+                msg,
+            } => write!(f, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl Error for FastqError {}
+
+impl From<io::Error> for FastqError {
+    fn from(e: io::Error) -> FastqError {
+        FastqError::Io(e)
+    }
+}
+
+// --------------------------------------------------
+/// The parts of a FASTQ record; `head` is the header minus the "@"
+pub trait Record {
+    fn head(&self) -> &[u8];
+    fn seq(&self) -> &[u8];
+    fn qual(&self) -> &[u8];
+
+    /// The header up to the first whitespace
+    fn id(&self) -> &[u8] {
+        let head = self.head();
+        let end = head
+            .iter()
+            .position(|c| c.is_ascii_whitespace())
+            .unwrap_or(head.len());
+        &head[..end]
+    }
+
+    /// The header after the ID, e.g., Casava 1.8's "1:N:0:ATCACG"
+    fn desc(&self) -> Option<&[u8]> {
+        let head = self.head();
+        let id_len = self.id().len();
+        if id_len < head.len() {
+            Some(&head[id_len + 1..])
+        } else {
+            None
+        }
+    }
+
+    fn to_owned_record(&self) -> OwnedRecord {
+        OwnedRecord {
+            head: self.head().to_vec(),
+            seq: self.seq().to_vec(),
+            qual: self.qual().to_vec(),
+        }
+    }
+}
+
+/// A record borrowing the reader's buffer
+#[derive(Debug, Clone, Copy)]
+pub struct RefRecord<'a> {
+    buf: &'a [u8],
+    head: (usize, usize),
+    seq: (usize, usize),
+    qual: (usize, usize),
+}
+
+impl<'a> Record for RefRecord<'a> {
+    fn head(&self) -> &[u8] {
+        &self.buf[self.head.0..self.head.1]
+    }
+
+    fn seq(&self) -> &[u8] {
+        &self.buf[self.seq.0..self.seq.1]
+    }
+
+    fn qual(&self) -> &[u8] {
+        &self.buf[self.qual.0..self.qual.1]
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OwnedRecord {
+    pub head: Vec<u8>,
+    pub seq: Vec<u8>,
+    pub qual: Vec<u8>,
+}
+
+impl Record for OwnedRecord {
+    fn head(&self) -> &[u8] {
+        &self.head
+    }
+
+    fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    fn qual(&self) -> &[u8] {
+        &self.qual
+    }
+}
+
+// --------------------------------------------------
+pub struct Reader<R: BufRead> {
+    input: R,
+    name: Option<String>,
+    buf: Vec<u8>,
+    line: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Reader<R> {
+        Reader {
+            input,
+            name: None,
+            buf: vec![],
+            line: 0,
+        }
+    }
+
+    /// Names the input (e.g., the path) in error messages
+    pub fn with_name(mut self, name: &str) -> Reader<R> {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// The number of lines read so far
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Reads the next record, skipping blank lines between records
+    pub fn next_record(&mut self) -> Option<Result<RefRecord<'_>, FastqError>> {
+        self.buf.clear();
+
+        let head = loop {
+            match self.read_line() {
+                Ok(Some(range)) if range.is_empty() => {
+                    self.buf.clear();
+                }
+                Ok(Some(range)) => break range,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        };
+        let head_line = self.line;
+
+        if self.buf[head.start] != b'@' {
+            return Some(Err(self.error(head_line, "expected \"@\" header")));
+        }
+
+        let mut lines = vec![];
+        for _ in 0..3 {
+            match self.read_line() {
+                Ok(Some(range)) => lines.push(range),
+                Ok(None) => {
+                    return Some(Err(
+                        self.error(head_line, "truncated FASTQ record")
+                    ))
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        let (seq, sep, qual) =
+            (lines[0].clone(), lines[1].clone(), lines[2].clone());
+        if sep.is_empty() || self.buf[sep.start] != b'+' {
+            return Some(Err(self.error(head_line + 2, "expected \"+\" line")));
+        }
+
+        if seq.len() != qual.len() {
+            let msg = format!(
+                "sequence length {} does not match quality length {}",
+                seq.len(),
+                qual.len()
+            );
+            return Some(Err(self.error(head_line + 3, &msg)));
+        }
+
+        Some(Ok(RefRecord {
+            buf: &self.buf,
+            head: (head.start + 1, head.end),
+            seq: (seq.start, seq.end),
+            qual: (qual.start, qual.end),
+        }))
+    }
+
+    /// Iterates over owned copies of the records
+    pub fn records(self) -> Records<R> {
+        Records { reader: self }
+    }
+
+    /// Appends a line to the buffer and returns its range minus the
+    /// line ending, or `None` at the end of the input
+    fn read_line(&mut self) -> Result<Option<Range<usize>>, FastqError> {
+        let start = self.buf.len();
+        if self.input.read_until(b'\n', &mut self.buf)? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+
+        let mut end = self.buf.len();
+        while end > start && matches!(self.buf[end - 1], b'\n' | b'\r') {
+            end -= 1;
+        }
+        Ok(Some(start..end))
+    }
+
+    fn error(&self, line: usize, msg: &str) -> FastqError {
+        FastqError::Format {
+            name: self.name.clone(),
+            line,
+            msg: msg.to_string(),
+        }
+    }
+}
+
+pub struct Records<R: BufRead> {
+    reader: Reader<R>,
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = Result<OwnedRecord, FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader
+            .next_record()
+            .map(|res| res.map(|rec| rec.to_owned_record()))
+    }
+}
+
+// --------------------------------------------------
+/// Opens a plain or compressed FASTQ file
+pub fn open(
+    path: &Path,
+) -> Result<Reader<Box<dyn BufRead + Send>>, FastqError> {
+    let file = File::open(path).map_err(|e| {
+        FastqError::Io(io::Error::new(
+            e.kind(),
+            format!("{}: {}", path.display(), e),
+        ))
+    })?;
+
+    let reader = decompress(BufReader::new(file))?;
+    Ok(Reader::new(reader).with_name(&path.display().to_string()))
+}
+
+/// Wraps the input in a decoder chosen by peeking at the magic bytes
+pub fn decompress<R: BufRead + Send + 'static>(
+    mut input: R,
+) -> io::Result<Box<dyn BufRead + Send>> {
+    let compression = Compression::from_magic(input.fill_buf()?);
+    Ok(match compression {
+        Compression::None => Box::new(input),
+        Compression::Gzip => {
+            Box::new(BufReader::new(MultiGzDecoder::new(input)))
+        }
+        Compression::Bzip2 => {
+            Box::new(BufReader::new(MultiBzDecoder::new(input)))
+        }
+        Compression::Zstd => Box::new(BufReader::new(
+            zstd::stream::read::Decoder::with_buffer(input)?,
+        )),
+    })
+}
+
+// --------------------------------------------------
+pub struct Writer<W: Write> {
+    output: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(output: W) -> Writer<W> {
+        Writer { output }
+    }
+
+    pub fn write<T: Record>(&mut self, record: &T) -> io::Result<()> {
+        self.write_parts(record.head(), record.seq(), record.qual())
+    }
+
+    pub fn write_parts(
+        &mut self,
+        head: &[u8],
+        seq: &[u8],
+        qual: &[u8],
+    ) -> io::Result<()> {
+        self.output.write_all(b"@")?;
+        self.output.write_all(head)?;
+        self.output.write_all(b"\n")?;
+        self.output.write_all(seq)?;
+        self.output.write_all(b"\n+\n")?;
+        self.output.write_all(qual)?;
+        self.output.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// Creates a FASTQ file compressed according to its extension. The
+/// compressed stream is finished when the writer is dropped; call
+/// `flush` first to see any errors.
+pub fn create(path: &Path) -> io::Result<Writer<Box<dyn Write + Send>>> {
+    let file = BufWriter::new(File::create(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+    })?);
+
+    let output: Box<dyn Write + Send> = match Compression::from_path(path) {
+        Compression::None => Box::new(file),
+        Compression::Gzip => {
+            Box::new(GzEncoder::new(file, flate2::Compression::default()))
+        }
+        Compression::Bzip2 => {
+            Box::new(BzEncoder::new(file, bzip2::Compression::default()))
+        }
+        Compression::Zstd => {
+            Box::new(zstd::stream::write::Encoder::new(file, 0)?.auto_finish())
+        }
+    };
+
+    Ok(Writer::new(output))
+}
+
+// --------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str =
+        "@r1 1:N:0:ATCACG\nACGT\n+\nIIII\n\n@r2\r\nGG\r\n+r2\r\nHH\r\n";
+
+    #[test]
+    fn test_reader() {
+        let mut reader = Reader::new(TEXT.as_bytes());
+
+        let rec = reader.next_record().unwrap().unwrap();
+        assert_eq!(rec.head(), b"r1 1:N:0:ATCACG");
+        assert_eq!(rec.id(), b"r1");
+        assert_eq!(rec.desc(), Some(&b"1:N:0:ATCACG"[..]));
+        assert_eq!(rec.seq(), b"ACGT");
+        assert_eq!(rec.qual(), b"IIII");
+
+        let rec = reader.next_record().unwrap().unwrap();
+        assert_eq!(rec.id(), b"r2");
+        assert_eq!(rec.desc(), None);
+        assert_eq!(rec.seq(), b"GG");
+        assert_eq!(rec.qual(), b"HH");
+
+        assert!(reader.next_record().is_none());
+        assert_eq!(reader.line(), 9);
+    }
+
+    #[test]
+    fn test_reader_errors() {
+        let err = |text: &str| -> String {
+            let reader = Reader::new(text.as_bytes()).with_name("x.fq");
+            match reader.records().find_map(Result::err) {
+                Some(e) => e.to_string(),
+                _ => String::new(),
+            }
+        };
+
+        assert_eq!(err("@r1\nAC\n+\nII\n"), "");
+        assert_eq!(
+            err("@r1\nAC\n+\nII\nr2\nAC\n+\nII\n"),
+            "x.fq: line 5: expected \"@\" header"
+        );
+        assert_eq!(err("@r1\nAC\n+\n"), "x.fq: line 1: truncated FASTQ record");
+        assert_eq!(
+            err("@r1\nAC\n-\nII\n"),
+            "x.fq: line 3: expected \"+\" line"
+        );
+        assert_eq!(
+            err("@r1\nACG\n+\nII\n"),
+            "x.fq: line 4: sequence length 3 does not match quality length 2"
+        );
+    }
+
+    #[test]
+    fn test_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let records: Vec<OwnedRecord> = Reader::new(TEXT.as_bytes())
+            .records()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        for name in &["x.fq", "x.fq.gz", "x.fq.bz2", "x.fq.zst"] {
+            let path = dir.path().join(name);
+            {
+                let mut writer = create(&path).unwrap();
+                for rec in &records {
+                    writer.write(rec).unwrap();
+                }
+                writer.flush().unwrap();
+            }
+
+            let mut magic = vec![0; 4];
+            let n = io::Read::read(&mut File::open(&path).unwrap(), &mut magic)
+                .unwrap();
+            assert_eq!(
+                Compression::from_magic(&magic[..n]),
+                Compression::from_path(&path)
+            );
+
+            let read: Vec<OwnedRecord> = open(&path)
+                .unwrap()
+                .records()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(read, records);
+        }
+
+        assert!(open(&dir.path().join("missing.fq")).is_err());
+    }
+}
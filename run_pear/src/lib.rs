@@ -1,24 +1,55 @@
+extern crate bzip2;
 extern crate clap;
+extern crate csv;
+extern crate flate2;
 extern crate glob;
+extern crate libc;
 extern crate regex;
+extern crate serde;
+extern crate serde_json;
+extern crate walkdir;
+extern crate zstd;
+
+pub mod fastq;
+pub mod merge;
+mod merger;
+pub mod score;
+mod state;
+pub mod trim;
 
 use clap::{App, Arg};
+use fastq::Record;
 use glob::glob;
+use merger::Merger;
 use regex::Regex;
+use serde::Serialize;
+use state::{RunState, State};
 use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{
     env,
-    fs::{self, DirBuilder},
-    io::Write,
+    fs::{self, DirBuilder, File},
+    io::{BufWriter, Write},
     path::{Path, PathBuf},
 };
+use walkdir::WalkDir;
 
 #[derive(Debug)]
 pub struct Config {
     query: Vec<String>,
     out_dir: PathBuf,
+    pear: String,
+    merger: &'static dyn Merger,
+    engine: Engine,
     p_value: Option<f32>,
     min_overlap: Option<u32>,
     max_assembly_length: Option<u32>,
@@ -36,23 +67,449 @@ pub struct Config {
     nbase: Option<bool>,
     keep_original: Option<bool>,
     stitch: Option<bool>,
+    stitch_spacer: Option<u32>,
     num_concurrent_jobs: Option<u32>,
     num_halt: Option<u32>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    retry_memory: Option<bool>,
+    retry_threads: Option<bool>,
+    job_timeout: Option<Duration>,
     resume: Option<bool>,
+    dry_run: Option<bool>,
+    plan_format: PlanFormat,
+    plan_out: Option<PathBuf>,
+    strict: Option<bool>,
+    pair_pattern: Vec<String>,
+    merge_lanes: Option<bool>,
+    manifest: Option<PathBuf>,
+    recursive: Option<bool>,
+    max_depth: Option<usize>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    follow_links: Option<bool>,
+    hidden: Option<bool>,
+    interleaved: Option<bool>,
+    verify_pairs: Option<bool>,
+    phred_records: Option<usize>,
+}
+
+/// How `find_files` looks for files in directories
+#[derive(Debug, Default)]
+struct FindOptions {
+    recursive: bool,
+    max_depth: Option<usize>,
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+    follow_links: bool,
+    hidden: bool,
+    skip_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlanFormat {
+    Text,
+    Shell,
+    Json,
+}
+
+/// Whether samples are merged by running an external merger or by the
+/// `merge` module
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Engine {
+    External,
+    Native,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 enum ReadDirection {
     Forward,
     Reverse,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+enum PearOption {
+    PValue(f32),
+    MinOverlap(u32),
+    MaxAssemblyLength(u32),
+    MinAssemblyLength(u32),
+    MinTrimLength(u32),
+    QualityThreshold(u32),
+    MaxUncalledBase(f32),
+    TestMethod(u32),
+    EmpiricalFreqs,
+    ScoreMethod(u32),
+    PhredBase(u32),
+    Memory(String),
+    Cap(u32),
+    Threads(u32),
+    Nbase,
+    KeepOriginal,
+    Stitch,
+    /// Native engine only, no pear argument
+    StitchSpacer(u32),
+}
+
+/// One invocation of a merger (or the native engine) for a sample
+#[derive(Debug, Clone)]
+struct PearJob {
+    sample: String,
+    engine: Engine,
+    merger: &'static dyn Merger,
+    program: String,
+    forward: PathBuf,
+    reverse: PathBuf,
+    out_prefix: PathBuf,
+    options: Vec<PearOption>,
+    interleaved: Option<PathBuf>,
+    /// How long an external merger may run before it is killed
+    timeout: Option<Duration>,
+    /// Where the job writes before its outputs are moved into place,
+    /// `None` to write in place
+    staging: Option<PathBuf>,
+    /// The prefix of the ".log" and ".err" files capturing the merger's
+    /// STDOUT and STDERR, `None` for no logs
+    log_prefix: Option<PathBuf>,
+}
+
+/// The command a job ran, staging prefix included, and what it printed
+#[derive(Debug, Default)]
+struct Captured {
+    command: Option<String>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum JobStatus {
+    Success,
+    Failed(Option<i32>),
+    Error(String),
+    Invalid(String),
+    TimedOut(Duration),
+    Interrupted,
+    Halted,
+}
+
+#[derive(Debug)]
+struct JobResult {
+    sample: String,
+    status: JobStatus,
+    stats: Option<MergeStats>,
+    elapsed: Option<Duration>,
+    attempts: Vec<Attempt>,
+}
+
+/// One run of a job with the memory and threads it was given
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Attempt {
+    status: String,
+    seconds: f64,
+    memory: Option<String>,
+    threads: Option<u32>,
+}
+
+/// How failing jobs are retried: up to `retries` more times, waiting
+/// `delay` before the first retry and twice as long before each next,
+/// optionally doubling pear's memory and halving its threads each time.
+/// Only failed exits, invalid outputs and timeouts are retried, not
+/// errors such as a missing merger or interrupted jobs.
+#[derive(Debug, Clone, Default)]
+struct RetryPolicy {
+    retries: u32,
+    delay: Duration,
+    more_memory: bool,
+    fewer_threads: bool,
+}
+
+/// The jobs to run plus the samples skipped by `--resume`, the lane
+/// files to concatenate beforehand, the samples failing `--verify_pairs`
+/// with the reason and the PHRED bases detected by sample
+#[derive(Debug)]
+struct Plan {
+    jobs: Vec<PearJob>,
+    skipped: Vec<String>,
+    concats: Vec<Concat>,
+    rejected: Vec<(String, String)>,
+    phred: HashMap<String, u32>,
+}
+
+/// Lane files concatenated into one input file for pear
+#[derive(Debug, Clone, PartialEq)]
+struct Concat {
+    sample: String,
+    sources: Vec<String>,
+    target: PathBuf,
+}
+
+/// Complete pairs, samples missing a mate and files not matching the
+/// naming pattern
+#[derive(Debug)]
+struct Classified {
+    pairs: ReadPairLookup,
+    unpaired: ReadPairLookup,
+    unmatched: Vec<String>,
+    patterns: HashMap<String, String>,
+    illumina: HashMap<String, IlluminaMeta>,
+    lane_files: HashMap<String, LaneFiles>,
+}
+
+/// The sample sheet index and lanes from Illumina file names
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+struct IlluminaMeta {
+    index: Option<String>,
+    lanes: Vec<String>,
+}
+
+/// A named regex with "sample" and "read" capture groups
+#[derive(Debug)]
+struct PairPattern {
+    name: String,
+    regex: Regex,
+}
+
+/// The read counts and settings reported by pear on STDOUT
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+struct MergeStats {
+    version: Option<String>,
+    assembled: u64,
+    discarded: u64,
+    unassembled: u64,
+    total: u64,
+    assembled_pct: f64,
+    discarded_pct: f64,
+    unassembled_pct: f64,
+    parameters: Vec<(String, String)>,
+}
+
+impl MergeStats {
+    /// Statistics from record counts, without a version or parameters
+    fn from_counts(assembled: u64, discarded: u64, unassembled: u64) -> Self {
+        let total = assembled + discarded + unassembled;
+        let pct = |n: u64| {
+            if total == 0 {
+                0.
+            } else {
+                100. * n as f64 / total as f64
+            }
+        };
+        MergeStats {
+            version: None,
+            assembled,
+            discarded,
+            unassembled,
+            total,
+            assembled_pct: pct(assembled),
+            discarded_pct: pct(discarded),
+            unassembled_pct: pct(unassembled),
+            parameters: vec![],
+        }
+    }
+
+    /// The statistics in the layout of pear's STDOUT, which
+    /// `parse_pear_output` reads back
+    fn to_pear_output(&self) -> String {
+        let mut out = String::new();
+        for (key, value) in &self.parameters {
+            out += &format!("{:.<35}: {}\n", key, value);
+        }
+        out += "\n";
+        for (key, num, pct) in &[
+            ("Assembled reads", self.assembled, self.assembled_pct),
+            ("Discarded reads", self.discarded, self.discarded_pct),
+            (
+                "Not assembled reads",
+                self.unassembled,
+                self.unassembled_pct,
+            ),
+        ] {
+            out += &format!(
+                "{:.<35}: {} / {} ({:.3}%)\n",
+                format!("{} ", key),
+                num,
+                self.total,
+                pct
+            );
+        }
+        out
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobStatus::Success => write!(f, "ok"),
+            JobStatus::Failed(Some(code)) => {
+                write!(f, "failed (exit code {})", code)
+            }
+            JobStatus::Failed(None) => write!(f, "failed (killed by signal)"),
+            JobStatus::Error(e) => write!(f, "failed to start ({})", e),
+            JobStatus::Invalid(e) => write!(f, "bad output ({})", e),
+            JobStatus::TimedOut(limit) => {
+                write!(f, "timed out after {}s", limit.as_secs())
+            }
+            JobStatus::Interrupted => write!(f, "interrupted"),
+            JobStatus::Halted => write!(f, "not run (halted)"),
+        }
+    }
+}
+
+impl PearOption {
+    /// The name of the command-line argument and manifest column
+    fn name(&self) -> &'static str {
+        match self {
+            PearOption::PValue(_) => "p_value",
+            PearOption::MinOverlap(_) => "min_overlap",
+            PearOption::MaxAssemblyLength(_) => "max_assembly_length",
+            PearOption::MinAssemblyLength(_) => "min_assembly_length",
+            PearOption::MinTrimLength(_) => "min_trim_length",
+            PearOption::QualityThreshold(_) => "quality_threshold",
+            PearOption::MaxUncalledBase(_) => "max_uncalled_base",
+            PearOption::TestMethod(_) => "test_method",
+            PearOption::EmpiricalFreqs => "empirical_freqs",
+            PearOption::ScoreMethod(_) => "score_method",
+            PearOption::PhredBase(_) => "phred_base",
+            PearOption::Memory(_) => "memory",
+            PearOption::Cap(_) => "cap",
+            PearOption::Threads(_) => "threads",
+            PearOption::Nbase => "nbase",
+            PearOption::KeepOriginal => "keep_original",
+            PearOption::Stitch => "stitch",
+            PearOption::StitchSpacer(_) => "stitch_spacer",
+        }
+    }
+}
+
+impl PearJob {
+    /// The argument vector passed to `program`
+    fn args(&self) -> Vec<OsString> {
+        self.merger.args(
+            &self.forward,
+            &self.reverse,
+            &self.out_prefix,
+            &self.options,
+        )
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(self.args());
+        cmd
+    }
+}
+
+/// Renders the job as a shell-quoted command line
+impl fmt::Display for PearJob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut words = vec![shell_quote(&self.program)];
+        for arg in self.args() {
+            words.push(shell_quote(&arg.to_string_lossy()));
+        }
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SampleStatus {
+    Merged,
+    Skipped,
+    Failed,
+    TimedOut,
+    Interrupted,
+    Halted,
+    Unpaired,
+}
+
+/// One line of the batch summary
+#[derive(Debug, Serialize)]
+struct SampleReport {
+    sample: String,
+    status: SampleStatus,
+    pattern: Option<String>,
+    forward: Option<String>,
+    reverse: Option<String>,
+    illumina: Option<IlluminaMeta>,
+    stats: Option<MergeStats>,
+    seconds: Option<f64>,
+    attempts: Vec<Attempt>,
+    outputs: Vec<String>,
+    error: Option<String>,
+}
+
+impl fmt::Display for SampleStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            SampleStatus::Merged => "merged",
+            SampleStatus::Skipped => "skipped",
+            SampleStatus::Failed => "failed",
+            SampleStatus::TimedOut => "timed_out",
+            SampleStatus::Interrupted => "interrupted",
+            SampleStatus::Halted => "halted",
+            SampleStatus::Unpaired => "unpaired",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// Built-in pairing patterns tried in this order, then "generic". Each
+/// ends in a FASTQ extension so sidecar files (e.g., ".md5") never pair.
+const PAIR_PRESETS: &[(&str, &str)] = &[
+    (
+        "illumina",
+        concat!(
+            r"^(?P<sample>.+)_S(?P<index>\d+)",
+            r"(?:_L(?P<lane>\d{3}))?_R(?P<read>[12])_\d{3}",
+            r"\.(?:fq|fastq)(?:\.(?:gz|bz2|zst))?$"
+        ),
+    ),
+    (
+        "dot_r",
+        concat!(
+            r"^(?P<sample>.+)\.[Rr](?P<read>[12])",
+            r"\.(?:fq|fastq)(?:\.(?:gz|bz2|zst))?$"
+        ),
+    ),
+    (
+        "read",
+        concat!(
+            r"^(?P<sample>.+)[_.]read(?P<read>[12])",
+            r"\.(?:fq|fastq)(?:\.(?:gz|bz2|zst))?$"
+        ),
+    ),
+    (
+        "trimmed",
+        concat!(
+            r"^(?P<sample>.+)_(?P<read>[12])_trimmed",
+            r"\.(?:fq|fastq)(?:\.(?:gz|bz2|zst))?$"
+        ),
+    ),
+    (
+        "sra",
+        concat!(
+            r"^(?P<sample>.+)_(?P<read>[12])",
+            r"\.(?:fq|fastq)(?:\.(?:gz|bz2|zst))?$"
+        ),
+    ),
+];
+
 type MyResult<T> = Result<T, Box<dyn Error>>;
 type ReadPair = HashMap<ReadDirection, String>;
 type ReadPairLookup = HashMap<String, ReadPair>;
+type LaneFiles = HashMap<ReadDirection, Vec<String>>;
+type SampleParams = HashMap<String, Vec<(String, String)>>;
 
 // --------------------------------------------------
 pub fn get_args() -> MyResult<Config> {
+    get_args_from(env::args_os())
+}
+
+/// Parses the given command line, the program name first
+fn get_args_from<I, T>(args: I) -> MyResult<Config>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
     let matches = App::new("run_pear")
         .version("0.1.0")
         .author("Ken Youens-Clark")
@@ -63,9 +520,81 @@ pub fn get_args() -> MyResult<Config> {
                 .long("query")
                 .value_name("FILE_OR_DIR")
                 .help("File input or directory")
-                .required(true)
+                .required_unless("manifest")
                 .min_values(1),
         )
+        .arg(
+            Arg::with_name("manifest")
+                .short("M")
+                .long("manifest")
+                .value_name("FILE")
+                .conflicts_with("query")
+                .help("TSV/CSV with sample, forward, reverse columns"),
+        )
+        .arg(
+            Arg::with_name("recursive")
+                .short("r")
+                .long("recursive")
+                .help("Search directories recursively"),
+        )
+        .arg(
+            Arg::with_name("max_depth")
+                .short("D")
+                .long("max_depth")
+                .value_name("INT")
+                .help("Max depth of the recursive search"),
+        )
+        .arg(
+            Arg::with_name("include")
+                .short("I")
+                .long("include")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only use files matching the glob"),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .short("X")
+                .long("exclude")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Skip files and directories matching the glob"),
+        )
+        .arg(
+            Arg::with_name("follow_links")
+                .short("l")
+                .long("follow_links")
+                .help("Follow symlinked directories"),
+        )
+        .arg(
+            Arg::with_name("hidden")
+                .short("a")
+                .long("hidden")
+                .help("Include hidden files and directories"),
+        )
+        .arg(
+            Arg::with_name("interleaved")
+                .short("N")
+                .long("interleaved")
+                .conflicts_with("manifest")
+                .help("Each input file is an interleaved pair"),
+        )
+        .arg(
+            Arg::with_name("verify_pairs")
+                .short("V")
+                .long("verify_pairs")
+                .help("Check that mates have the same read IDs"),
+        )
+        .arg(
+            Arg::with_name("phred_records")
+                .short("B")
+                .long("phred_records")
+                .value_name("INT")
+                .default_value("10000")
+                .help("Records read to detect the PHRED base (0 to disable)"),
+        )
         .arg(
             Arg::with_name("out_dir")
                 .short("o")
@@ -73,12 +602,36 @@ pub fn get_args() -> MyResult<Config> {
                 .value_name("DIR")
                 .help("Output directory"),
         )
+        .arg(
+            Arg::with_name("pear")
+                .short("P")
+                .long("pear")
+                .value_name("PATH")
+                .help("Path to the merger (default: its name, e.g., pear)"),
+        )
+        .arg(
+            Arg::with_name("merger")
+                .long("merger")
+                .value_name("MERGER")
+                .possible_values(&["pear", "flash", "ngmerge", "vsearch"])
+                .default_value("pear")
+                .help("The external merger to run"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .short("E")
+                .long("engine")
+                .value_name("ENGINE")
+                .possible_values(&["external", "native"])
+                .default_value("external")
+                .help("Merge with the external merger or the built-in engine"),
+        )
         .arg(
             Arg::with_name("p_value")
                 .short("p")
                 .long("p_value")
                 .value_name("FLOAT")
-                .help("P-value"),
+                .help("P-value: 0.0001, 0.001, 0.01, 0.05 or 1.0"),
         )
         .arg(
             Arg::with_name("min_overlap")
@@ -188,13 +741,19 @@ pub fn get_args() -> MyResult<Config> {
                 .long("stitch")
                 .help("concatenate reads"),
         )
+        .arg(
+            Arg::with_name("stitch_spacer")
+                .long("stitch_spacer")
+                .value_name("INT")
+                .help("Ns between stitched reads (native engine)"),
+        )
         .arg(
             Arg::with_name("num_concurrent_jobs")
                 .short("J")
                 .long("num_concurrent_jobs")
                 .value_name("INT")
                 .default_value("8")
-                .help("Number of concurrent jobs for parallel"),
+                .help("Number of concurrent jobs"),
         )
         .arg(
             Arg::with_name("num_halt")
@@ -205,12 +764,90 @@ pub fn get_args() -> MyResult<Config> {
                 .help("Halt after this many failing jobs"),
         )
         .arg(
-            Arg::with_name("resume")
-                .short("R")
-                .long("resume")
-                .help("Resume batch, do not overwrite existing"),
+            Arg::with_name("job_timeout")
+                .long("job_timeout")
+                .value_name("TIME")
+                .help(
+                    "Kill an external merger after this long, e.g., 90m \
+                     (native merges always run to the end)",
+                ),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .value_name("INT")
+                .default_value("0")
+                .help("Retry a failing job this many times"),
+        )
+        .arg(
+            Arg::with_name("retry_delay")
+                .long("retry_delay")
+                .value_name("SECS")
+                .default_value("10")
+                .help("Wait before the first retry, doubling up to an hour"),
+        )
+        .arg(
+            Arg::with_name("retry_memory")
+                .long("retry_memory")
+                .help("Double --memory (or a manifest's) on each retry"),
+        )
+        .arg(
+            Arg::with_name("retry_threads")
+                .long("retry_threads")
+                .help("Halve the threads on each retry"),
+        )
+        .arg(
+            Arg::with_name("resume").short("R").long("resume").help(
+                "Skip samples completed with the same inputs and settings",
+            ),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .short("d")
+                .long("dry_run")
+                .help("Show the jobs to run, do not run them"),
+        )
+        .arg(
+            Arg::with_name("plan_format")
+                .short("F")
+                .long("plan_format")
+                .value_name("FORMAT")
+                .possible_values(&["text", "sh", "json"])
+                .default_value("text")
+                .help("Format of the dry run plan"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("plan_out")
+                .short("w")
+                .long("plan_out")
+                .value_name("FILE")
+                .help("Write the dry run plan to a file"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .short("S")
+                .long("strict")
+                .help("Fail on unpaired or unrecognized input files"),
+        )
+        .arg(
+            Arg::with_name("pair_pattern")
+                .short("x")
+                .long("pair_pattern")
+                .value_name("REGEX_OR_PRESET")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Regex with \"sample\" and \"read\" groups or preset \
+                     (illumina, dot_r, read, trimmed, sra, generic)",
+                ),
+        )
+        .arg(
+            Arg::with_name("merge_lanes")
+                .short("L")
+                .long("merge_lanes")
+                .help("Concatenate the lanes of Illumina samples"),
+        )
+        .get_matches_from(args);
 
     let out_dir = match matches.value_of("out_dir") {
         Some(x) => PathBuf::from(x),
@@ -262,11 +899,7 @@ pub fn get_args() -> MyResult<Config> {
         .value_of("phred_base")
         .and_then(|x| x.trim().parse::<u32>().ok());
 
-    let memory = if let Some(memory) = matches.value_of("memory") {
-        Some(memory.to_string())
-    } else {
-        None
-    };
+    let memory = matches.value_of("memory").map(|x| x.to_string());
 
     let cap = matches
         .value_of("cap")
@@ -282,8 +915,39 @@ pub fn get_args() -> MyResult<Config> {
 
     let stitch = Some(matches.is_present("stitch"));
 
+    let stitch_spacer = matches
+        .value_of("stitch_spacer")
+        .and_then(|x| x.trim().parse::<u32>().ok());
+
     let resume = Some(matches.is_present("resume"));
 
+    let dry_run = Some(matches.is_present("dry_run"));
+
+    let engine = match matches.value_of("engine") {
+        Some("native") => Engine::Native,
+        _ => Engine::External,
+    };
+
+    let merger = matches
+        .value_of("merger")
+        .and_then(merger::find)
+        .unwrap_or(&merger::Pear);
+
+    let plan_format = match matches.value_of("plan_format") {
+        Some("sh") => PlanFormat::Shell,
+        Some("json") => PlanFormat::Json,
+        _ => PlanFormat::Text,
+    };
+
+    let plan_out = matches.value_of("plan_out").map(PathBuf::from);
+
+    let strict = Some(matches.is_present("strict"));
+
+    let pair_pattern =
+        matches.values_of_lossy("pair_pattern").unwrap_or_default();
+
+    let merge_lanes = Some(matches.is_present("merge_lanes"));
+
     let num_concurrent_jobs = matches
         .value_of("num_concurrent_jobs")
         .and_then(|x| x.trim().parse::<u32>().ok());
@@ -292,11 +956,43 @@ pub fn get_args() -> MyResult<Config> {
         .value_of("num_halt")
         .and_then(|x| x.trim().parse::<u32>().ok());
 
+    let job_timeout = match matches.value_of("job_timeout") {
+        Some(value) => Some(parse_duration(value).ok_or_else(|| {
+            format!("--job_timeout \"{}\" is not a duration", value)
+        })?),
+        _ => None,
+    };
+
+    let retries = matches
+        .value_of("retries")
+        .and_then(|x| x.trim().parse::<u32>().ok());
+
+    let retry_delay = matches
+        .value_of("retry_delay")
+        .and_then(|x| x.trim().parse::<u64>().ok());
+
+    // Without a memory size there is nothing to double
+    let retry_memory = matches.is_present("retry_memory");
+    if retry_memory && memory.is_none() && !matches.is_present("manifest") {
+        return Err(From::from("--retry_memory needs --memory"));
+    }
+
     Ok(Config {
-        query: matches.values_of_lossy("query").unwrap(),
+        query: matches.values_of_lossy("query").unwrap_or_default(),
         out_dir,
+        pear: matches
+            .value_of("pear")
+            .unwrap_or_else(|| merger.program())
+            .to_string(),
+        merger,
+        engine,
         num_concurrent_jobs,
         num_halt,
+        retries,
+        retry_delay,
+        retry_memory: Some(retry_memory),
+        retry_threads: Some(matches.is_present("retry_threads")),
+        job_timeout,
         p_value,
         min_overlap,
         max_assembly_length,
@@ -314,315 +1010,2775 @@ pub fn get_args() -> MyResult<Config> {
         nbase,
         keep_original,
         stitch,
+        stitch_spacer,
         resume,
+        dry_run,
+        plan_format,
+        plan_out,
+        strict,
+        pair_pattern,
+        merge_lanes,
+        manifest: matches.value_of("manifest").map(PathBuf::from),
+        recursive: Some(matches.is_present("recursive")),
+        max_depth: matches
+            .value_of("max_depth")
+            .and_then(|x| x.trim().parse::<usize>().ok()),
+        include: matches.values_of_lossy("include").unwrap_or_default(),
+        exclude: matches.values_of_lossy("exclude").unwrap_or_default(),
+        follow_links: Some(matches.is_present("follow_links")),
+        hidden: Some(matches.is_present("hidden")),
+        interleaved: Some(matches.is_present("interleaved")),
+        verify_pairs: Some(matches.is_present("verify_pairs")),
+        phred_records: matches
+            .value_of("phred_records")
+            .and_then(|x| x.trim().parse::<usize>().ok()),
     })
 }
 
 // --------------------------------------------------
 pub fn run(config: Config) -> MyResult<()> {
-    let files = find_files(&config.query)?;
-
-    if files.is_empty() {
-        let msg = format!("No input files from query \"{:?}\"", &config.query);
-        return Err(From::from(msg));
-    }
+    let (classified, params) = match &config.manifest {
+        Some(manifest) => read_manifest(manifest)?,
+        _ => {
+            let files = find_files(&config.query, &find_options(&config)?)?;
 
-    let pairs = classify(&files)?;
+            if files.is_empty() {
+                let msg = format!(
+                    "No input files from query \"{:?}\"",
+                    &config.query
+                );
+                return Err(From::from(msg));
+            }
 
-    println!("Processing {} pair.", pairs.keys().len());
+            let classified = if config.interleaved.unwrap_or(false) {
+                classify_interleaved(&files)?
+            } else {
+                classify(
+                    &files,
+                    &config.pair_pattern,
+                    config.merge_lanes.unwrap_or(false),
+                )?
+            };
+            (classified, HashMap::new())
+        }
+    };
+    check_classified(&classified, config.strict.unwrap_or(false))?;
 
-    let jobs = make_jobs(&config, pairs)?;
+    println!("Processing {} pair.", classified.pairs.keys().len());
 
-    run_jobs(
-        &jobs,
-        "Running pear",
-        config.num_concurrent_jobs.unwrap_or(8),
-        config.num_halt.unwrap_or(1),
-    )?;
+    let mut pattern_counts: Vec<(&String, usize)> = vec![];
+    for sample in classified.pairs.keys() {
+        if let Some(name) = classified.patterns.get(sample) {
+            match pattern_counts.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, count)) => *count += 1,
+                _ => pattern_counts.push((name, 1)),
+            }
+        }
+    }
+    pattern_counts.sort();
+    for (name, count) in pattern_counts {
+        println!(
+            "  {} sample{} paired by \"{}\"",
+            count,
+            if count == 1 { "" } else { "s" },
+            name
+        );
+    }
 
-    println!("Done, see output in \"{}\"", &config.out_dir.display());
+    let mut pairs = classified.pairs.clone();
+    let concats = plan_concats(&classified, &config.out_dir, &mut pairs);
 
-    Ok(())
-}
+    let mut plan = make_jobs(&config, &pairs, &params)?;
+    let phred_records = config.phred_records.unwrap_or(0);
 
-// --------------------------------------------------
-fn make_jobs(
-    config: &Config,
-    pairs: ReadPairLookup,
-) -> Result<Vec<String>, Box<dyn Error>> {
-    let mut args: Vec<String> = vec![];
-    if let Some(p_value) = config.p_value {
-        args.push(format!("-p {}", p_value));
+    // Resume first so only the jobs that will run are verified and read
+    let mut state = State::load(&config.out_dir)?;
+    clean_staging(&config.out_dir, config.dry_run.unwrap_or(false))?;
+    if config.resume.unwrap_or(false) {
+        resume_plan(&mut plan, &state, &classified, phred_records > 0);
     }
 
-    if let Some(min_overlap) = config.min_overlap {
-        args.push(format!("-v {}", min_overlap));
+    if config.verify_pairs.unwrap_or(false) {
+        verify_plan(
+            &mut plan,
+            &classified,
+            config.num_concurrent_jobs.unwrap_or(8) as usize,
+        );
     }
 
-    if let Some(max_assembly_length) = config.max_assembly_length {
-        args.push(format!("-m {}", max_assembly_length));
+    if phred_records > 0 {
+        detect_plan_phred(
+            &mut plan,
+            &classified,
+            phred_records,
+            config.strict.unwrap_or(false),
+            config.num_concurrent_jobs.unwrap_or(8) as usize,
+        );
     }
 
-    if let Some(min_assembly_length) = config.min_assembly_length {
-        args.push(format!("-n {}", min_assembly_length));
-    }
+    plan.concats = concats
+        .into_iter()
+        .filter(|c| plan.jobs.iter().any(|j| j.sample == c.sample))
+        .collect();
+    let jobs = &plan.jobs;
 
-    if let Some(min_trim_length) = config.min_trim_length {
-        args.push(format!("-t {}", min_trim_length));
+    if config.dry_run.unwrap_or(false) {
+        return write_plan(&plan, config.plan_format, &config.plan_out);
     }
 
-    if let Some(quality_threshold) = config.quality_threshold {
-        args.push(format!("-q {}", quality_threshold));
+    for job in jobs {
+        if let Some(dir) = job.out_prefix.parent() {
+            DirBuilder::new().recursive(true).create(dir)?;
+        }
     }
 
-    if let Some(max_uncalled_base) = config.max_uncalled_base {
-        args.push(format!("-u {}", max_uncalled_base));
+    for concat in &plan.concats {
+        println!(
+            "Concatenating {} lane files into \"{}\"",
+            concat.sources.len(),
+            concat.target.display()
+        );
+        concat_files(&concat.sources, &concat.target)?;
     }
 
-    if let Some(test_method) = config.test_method {
-        args.push(format!("-g {}", test_method));
+    handle_signals();
+    let msg = match config.engine {
+        Engine::External => format!("Running {}", config.merger.name()),
+        Engine::Native => "Merging".to_string(),
+    };
+    for job in jobs {
+        state.start(
+            &job.sample,
+            &job.to_string(),
+            &sample_inputs(&classified, &job.sample),
+            plan.phred.get(&job.sample).copied(),
+        )?;
     }
+    state.save()?;
 
-    if let Some(empirical_freqs) = config.empirical_freqs {
-        if empirical_freqs {
-            args.push("-e".to_string());
+    let results = run_jobs(
+        jobs,
+        &msg,
+        config.num_concurrent_jobs.unwrap_or(8),
+        config.num_halt.unwrap_or(1),
+        &RetryPolicy {
+            retries: config.retries.unwrap_or(0),
+            delay: Duration::from_secs(config.retry_delay.unwrap_or(10)),
+            more_memory: config.retry_memory.unwrap_or(false),
+            fewer_threads: config.retry_threads.unwrap_or(false),
+        },
+        |job, result| {
+            remove_concats(&plan.concats, &job.sample);
+            let status = match result.status {
+                JobStatus::Success => RunState::Completed,
+                JobStatus::TimedOut(_) => RunState::TimedOut,
+                JobStatus::Halted | JobStatus::Interrupted => {
+                    RunState::Interrupted
+                }
+                _ => RunState::Failed,
+            };
+            let outputs: Vec<PathBuf> = output_files(&job.out_prefix)
+                .into_iter()
+                .map(PathBuf::from)
+                .collect();
+            if let Err(e) = state
+                .finish(&job.sample, status, &outputs)
+                .and_then(|_| state.save())
+            {
+                eprintln!("Warning: cannot save the batch state: {}", e);
+            }
+        },
+    )?;
+
+    for (job, result) in jobs.iter().zip(&results) {
+        let status = match result.attempts.len() {
+            n if n > 1 => format!("{} after {} attempts", result.status, n),
+            _ => result.status.to_string(),
+        };
+        match &result.stats {
+            Some(stats) => println!(
+                "{}: {} (assembled {} / {}, {:.3}%)",
+                result.sample,
+                status,
+                stats.assembled,
+                stats.total,
+                stats.assembled_pct
+            ),
+            _ => println!("{}: {}", result.sample, status),
+        }
+        if result.status != JobStatus::Success {
+            eprintln!("  {}", job);
+            if let Some(prefix) = &job.log_prefix {
+                let path = with_extension(prefix, "err");
+                for line in tail_lines(&path, 10) {
+                    eprintln!("  | {}", line);
+                }
+            }
         }
     }
 
-    if let Some(score_method) = config.score_method {
-        args.push(format!("-s {}", score_method));
-    }
+    let reports = make_reports(&config.out_dir, &classified, &plan, &results);
+    write_summary(&config.out_dir, &reports)?;
 
-    if let Some(phred_base) = config.phred_base {
-        args.push(format!("-b {}", phred_base));
+    if interrupted() {
+        return Err(From::from("Interrupted, rerun with --resume to finish"));
     }
 
-    if let Some(memory) = &config.memory {
-        args.push(format!("-y {}", memory));
-    }
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|r| r.status != JobStatus::Success)
+        .map(|r| r.sample.as_str())
+        .chain(plan.rejected.iter().map(|(sample, _)| sample.as_str()))
+        .collect();
 
-    if let Some(cap) = config.cap {
-        args.push(format!("-c {}", cap));
+    if !failed.is_empty() {
+        let num_jobs = results.len() + plan.rejected.len();
+        let msg = format!(
+            "{} of {} job{} did not succeed: {}",
+            failed.len(),
+            num_jobs,
+            if num_jobs == 1 { "" } else { "s" },
+            failed.join(", ")
+        );
+        return Err(From::from(msg));
     }
 
-    if let Some(threads) = config.threads {
-        args.push(format!("-j {}", threads));
-    }
+    println!("Done, see output in \"{}\"", &config.out_dir.display());
 
-    if let Some(nbase) = config.nbase {
-        if nbase {
-            args.push("-z".to_string());
-        }
-    }
+    Ok(())
+}
 
-    if let Some(keep_original) = config.keep_original {
-        if keep_original {
-            args.push("-k".to_string());
-        }
-    }
+// --------------------------------------------------
+fn make_jobs(
+    config: &Config,
+    pairs: &ReadPairLookup,
+    params: &SampleParams,
+) -> Result<Plan, Box<dyn Error>> {
+    let options = pear_options(config);
+
+    // The native engine writes pear's layout
+    let merger: &'static dyn Merger = match config.engine {
+        Engine::External => config.merger,
+        Engine::Native => &merger::Pear,
+    };
 
-    if let Some(stitch) = config.stitch {
-        if stitch {
-            args.push("-i".to_string());
+    if config.engine == Engine::External {
+        let unsupported: Vec<&str> = options
+            .iter()
+            .filter(|opt| config.merger.option_args(opt).is_none())
+            .map(PearOption::name)
+            .collect();
+        if !unsupported.is_empty() {
+            eprintln!(
+                "Warning: {} ignores {}",
+                config.merger.name(),
+                unsupported.join(", ")
+            );
         }
     }
 
-    let resume = config.resume.unwrap_or(false);
+    let interleaved = config.interleaved.unwrap_or(false);
+
+    let mut jobs: Vec<PearJob> = vec![];
+    let mut samples: Vec<&String> = pairs.keys().collect();
+    samples.sort();
 
-    let mut jobs: Vec<String> = vec![];
-    for (i, (sample, val)) in pairs.iter().enumerate() {
+    for (i, sample) in samples.into_iter().enumerate() {
         println!("{:3}: {}", i + 1, sample);
+        let val = &pairs[sample];
 
         if let (Some(fwd), Some(rev)) = (
             val.get(&ReadDirection::Forward),
             val.get(&ReadDirection::Reverse),
         ) {
             let out_dir = &config.out_dir.join(sample);
-            if !out_dir.is_dir() {
-                DirBuilder::new().recursive(true).create(&out_dir)?;
+            let mut options = options.clone();
+            for (name, value) in params.get(sample).unwrap_or(&vec![]) {
+                set_pear_option(&mut options, name, value)?;
             }
 
-            let existing: Vec<String> =
-                glob(&format!("{}/*.assembled.*", &out_dir.display()))?
-                    .filter_map(Result::ok)
-                    .map(|e| e.display().to_string())
-                    .collect();
+            // Interleaved files are split into hidden temporary files
+            let (forward, reverse, source) = if interleaved {
+                (
+                    out_dir.join(format!(".{}_R1.tmp.fastq", sample)),
+                    out_dir.join(format!(".{}_R2.tmp.fastq", sample)),
+                    Some(PathBuf::from(fwd)),
+                )
+            } else {
+                (PathBuf::from(fwd), PathBuf::from(rev), None)
+            };
 
-            if resume && !existing.is_empty() {
-                eprintln!("Skipping {}", sample);
-                continue;
-            }
+            let program = match config.engine {
+                Engine::External => config.pear.to_string(),
+                Engine::Native => {
+                    merge_params(&options)
+                        .map_err(|e| format!("{}: {}", sample, e))?;
+                    "native".to_string()
+                }
+            };
 
-            let out_file = out_dir.join(sample);
-            jobs.push(format!(
-                "pear -f {} -r {} -o {} {}",
-                fwd,
-                rev,
-                out_file.display(),
-                args.join(" "),
-            ));
+            jobs.push(PearJob {
+                sample: sample.to_string(),
+                engine: config.engine,
+                merger,
+                program,
+                forward,
+                reverse,
+                out_prefix: out_dir.join(sample),
+                options,
+                interleaved: source,
+                timeout: config.job_timeout,
+                staging: Some(
+                    config
+                        .out_dir
+                        .join(state::STATE_DIR)
+                        .join("staging")
+                        .join(sample),
+                ),
+                log_prefix: Some(out_dir.join(match config.engine {
+                    Engine::External => merger.name(),
+                    Engine::Native => "native",
+                })),
+            });
         }
     }
 
-    Ok(jobs)
+    if config.retry_memory.unwrap_or(false) {
+        let missing: Vec<&str> = jobs
+            .iter()
+            .filter(|job| {
+                !job.options
+                    .iter()
+                    .any(|opt| matches!(opt, PearOption::Memory(_)))
+            })
+            .map(|job| job.sample.as_str())
+            .collect();
+        if !missing.is_empty() {
+            eprintln!(
+                "Warning: --retry_memory has no memory to double for {}",
+                missing.join(", ")
+            );
+        }
+    }
+
+    Ok(Plan {
+        jobs,
+        skipped: vec![],
+        concats: vec![],
+        rejected: vec![],
+        phred: HashMap::new(),
+    })
 }
 
 // --------------------------------------------------
-fn find_files(paths: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
-    let mut files = vec![];
-    for path in paths {
-        let meta = fs::metadata(path)?;
-        if meta.is_file() {
-            files.push(path.to_owned());
-        } else {
-            for entry in fs::read_dir(path)? {
-                let entry = entry?;
-                let meta = entry.metadata()?;
-                if meta.is_file() {
-                    files.push(entry.path().display().to_string());
-                }
+/// Moves the jobs the batch state shows completed with the same command
+/// and unchanged inputs and outputs to `plan.skipped`. This runs before
+/// PHRED detection, so if `detect_phred` the command is compared with
+/// the PHRED base the state recorded as detected, if any.
+fn resume_plan(
+    plan: &mut Plan,
+    state: &State,
+    classified: &Classified,
+    detect_phred: bool,
+) {
+    let jobs = std::mem::take(&mut plan.jobs);
+    for job in jobs {
+        let inputs = sample_inputs(classified, &job.sample);
+        let detected = state
+            .get(&job.sample)
+            .and_then(|entry| entry.phred_base)
+            .filter(|_| detect_phred);
+        let command = match detected {
+            Some(base) if !has_phred_base(&job) => {
+                let mut job = job.clone();
+                add_phred_base(&mut job, base);
+                job.to_string()
             }
+            _ => job.to_string(),
         };
-    }
+
+        match state.rerun_reason(&job.sample, &command, &inputs) {
+            None => {
+                eprintln!("Skipping {}", job.sample);
+                plan.skipped.push(job.sample);
+            }
+            Some(reason) => {
+                if state.get(&job.sample).is_some() {
+                    eprintln!("Rerunning {}: {}", job.sample, reason);
+                }
+                plan.jobs.push(job);
+            }
+        }
+    }
+}
+
+// --------------------------------------------------
+/// The original input files of a sample, all lanes for merged lanes
+fn sample_inputs(classified: &Classified, sample: &str) -> Vec<PathBuf> {
+    let mut inputs: Vec<PathBuf> = vec![];
+    for direction in &[ReadDirection::Forward, ReadDirection::Reverse] {
+        let files = match classified.lane_files.get(sample) {
+            Some(lanes) => lanes.get(direction).cloned().unwrap_or_default(),
+            _ => classified
+                .pairs
+                .get(sample)
+                .and_then(|pair| pair.get(direction))
+                .cloned()
+                .into_iter()
+                .collect(),
+        };
+        for file in files {
+            let path = PathBuf::from(file);
+            if !inputs.contains(&path) {
+                inputs.push(path);
+            }
+        }
+    }
+    inputs
+}
+
+// --------------------------------------------------
+/// Moves the jobs whose mates do not match to `plan.rejected`. The lanes
+/// of merged Illumina samples are checked pair by pair; interleaved
+/// files are not checked.
+fn verify_plan(plan: &mut Plan, classified: &Classified, num_threads: usize) {
+    let inputs: Vec<Vec<(PathBuf, PathBuf)>> = plan
+        .jobs
+        .iter()
+        .map(|job| match classified.lane_files.get(&job.sample) {
+            _ if job.interleaved.is_some() => vec![],
+            Some(lanes) => {
+                let files = |d: &ReadDirection| {
+                    lanes.get(d).cloned().unwrap_or_default().into_iter()
+                };
+                files(&ReadDirection::Forward)
+                    .zip(files(&ReadDirection::Reverse))
+                    .map(|(f, r)| (PathBuf::from(f), PathBuf::from(r)))
+                    .collect()
+            }
+            _ => vec![(job.forward.clone(), job.reverse.clone())],
+        })
+        .collect();
+
+    println!(
+        "Verifying {} pair{}",
+        inputs.len(),
+        if inputs.len() == 1 { "" } else { "s" }
+    );
+    let errors = par_map(&inputs, num_threads, |pairs| {
+        pairs
+            .iter()
+            .map(|(fwd, rev)| verify_pair(fwd, rev))
+            .find_map(Result::err)
+            .map(|e| e.to_string())
+    });
+
+    let jobs = std::mem::take(&mut plan.jobs);
+    for (job, error) in jobs.into_iter().zip(errors) {
+        match error {
+            Some(error) => {
+                eprintln!("Rejecting {}: {}", job.sample, error);
+                plan.rejected.push((job.sample, error));
+            }
+            _ => plan.jobs.push(job),
+        }
+    }
+}
+
+// --------------------------------------------------
+/// Sets the PHRED base of each job to the one detected in its input.
+/// Jobs whose mates disagree are moved to `plan.rejected`, as are those
+/// where the guess contradicts an explicit "phred_base" if `strict`
+/// (otherwise the explicit value is kept with a warning).
+fn detect_plan_phred(
+    plan: &mut Plan,
+    classified: &Classified,
+    num_records: usize,
+    strict: bool,
+    num_threads: usize,
+) {
+    let inputs: Vec<Vec<PathBuf>> = plan
+        .jobs
+        .iter()
+        .map(|job| {
+            match (&job.interleaved, classified.lane_files.get(&job.sample)) {
+                (Some(source), _) => vec![source.clone()],
+                (_, Some(lanes)) => {
+                    [ReadDirection::Forward, ReadDirection::Reverse]
+                        .iter()
+                        .filter_map(|d| lanes.get(d).and_then(|f| f.first()))
+                        .map(PathBuf::from)
+                        .collect()
+                }
+                _ => vec![job.forward.clone(), job.reverse.clone()],
+            }
+        })
+        .collect();
+
+    let guesses = par_map(&inputs, num_threads, |files| {
+        files
+            .iter()
+            .map(|file| {
+                detect_phred(file, num_records)
+                    .map(|guess| (file.display().to_string(), guess))
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<Vec<(String, Option<u32>)>, String>>()
+    });
+
+    let jobs = std::mem::take(&mut plan.jobs);
+    for (mut job, guesses) in jobs.into_iter().zip(guesses) {
+        let guesses = match guesses {
+            Ok(guesses) => guesses,
+            Err(e) => {
+                eprintln!("Rejecting {}: {}", job.sample, e);
+                plan.rejected.push((job.sample, e));
+                continue;
+            }
+        };
+
+        let mut found: Vec<u32> =
+            guesses.iter().filter_map(|(_, guess)| *guess).collect();
+        found.sort();
+        found.dedup();
+
+        let error = match found.as_slice() {
+            [] => None,
+            [guess] => {
+                let explicit = job.options.iter().find_map(|opt| match opt {
+                    PearOption::PhredBase(base) => Some(*base),
+                    _ => None,
+                });
+
+                match explicit {
+                    Some(base) if base != *guess => {
+                        let msg = format!(
+                            "phred_base is {} but the qualities look \
+                             like Phred+{}",
+                            base, guess
+                        );
+                        if strict {
+                            Some(msg)
+                        } else {
+                            eprintln!("Warning: {}: {}", job.sample, msg);
+                            None
+                        }
+                    }
+                    Some(_) => None,
+                    _ => {
+                        add_phred_base(&mut job, *guess);
+                        plan.phred.insert(job.sample.to_string(), *guess);
+                        None
+                    }
+                }
+            }
+            _ => {
+                let desc: Vec<String> = guesses
+                    .iter()
+                    .map(|(file, guess)| match guess {
+                        Some(guess) => format!("\"{}\" Phred+{}", file, guess),
+                        _ => format!("\"{}\" unknown", file),
+                    })
+                    .collect();
+                Some(format!(
+                    "mates disagree on PHRED base: {}",
+                    desc.join(", ")
+                ))
+            }
+        };
+
+        match error {
+            Some(error) => {
+                eprintln!("Rejecting {}: {}", job.sample, error);
+                plan.rejected.push((job.sample, error));
+            }
+            _ => plan.jobs.push(job),
+        }
+    }
+}
+
+// --------------------------------------------------
+/// Whether a job was given a PHRED base
+fn has_phred_base(job: &PearJob) -> bool {
+    job.options
+        .iter()
+        .any(|opt| matches!(opt, PearOption::PhredBase(_)))
+}
+
+/// Adds a detected PHRED base to a job, the same way for detection and
+/// for comparing with the batch state
+fn add_phred_base(job: &mut PearJob, base: u32) {
+    job.options.push(PearOption::PhredBase(base));
+}
+
+// --------------------------------------------------
+/// Checks that both files have the same number of records and that the
+/// read IDs agree, ignoring "/1" and "/2" suffixes and comments such as
+/// Casava 1.8's " 1:N:0:ATCACG"
+fn verify_pair(forward: &Path, reverse: &Path) -> MyResult<()> {
+    let mut fwd = fastq::open(forward)?;
+    let mut rev = fastq::open(reverse)?;
+    let mut num_records = 0;
+
+    loop {
+        let rec1 = fwd.next_record().transpose()?;
+        let rec2 = rev.next_record().transpose()?;
+        let id1 = rec1.as_ref().map(read_id);
+        let id2 = rec2.as_ref().map(read_id);
+        let id1_first = id1.is_some();
+        match (id1, id2) {
+            (None, None) => return Ok(()),
+            (Some(id1), Some(id2)) => {
+                if id1 != id2 {
+                    let msg = format!(
+                        "record {}: read ID \"{}\" in \"{}\" \
+                         does not match \"{}\" in \"{}\"",
+                        num_records + 1,
+                        String::from_utf8_lossy(id1),
+                        forward.display(),
+                        String::from_utf8_lossy(id2),
+                        reverse.display()
+                    );
+                    return Err(From::from(msg));
+                }
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                let (longer, shorter) = if id1_first {
+                    (forward, reverse)
+                } else {
+                    (reverse, forward)
+                };
+                let msg = format!(
+                    "\"{}\" has more than the {} records in \"{}\"",
+                    longer.display(),
+                    num_records,
+                    shorter.display()
+                );
+                return Err(From::from(msg));
+            }
+        }
+        num_records += 1;
+    }
+}
+
+// --------------------------------------------------
+/// The read ID minus any "/1" or "/2" suffix
+fn read_id<T: Record>(record: &T) -> &[u8] {
+    let id = record.id();
+    id.strip_suffix(b"/1")
+        .or_else(|| id.strip_suffix(b"/2"))
+        .unwrap_or(id)
+}
+
+// --------------------------------------------------
+/// Guesses the PHRED offset from the quality characters of the first
+/// `num_records`: anything below ";" can only be Phred+33, while
+/// anything above "K" with nothing below ";" is Phred+64. Returns
+/// `None` for an empty file or when the range fits both.
+fn detect_phred(path: &Path, num_records: usize) -> MyResult<Option<u32>> {
+    let mut reader = fastq::open(path)?;
+    let (mut min, mut max) = (u8::MAX, u8::MIN);
+
+    for _ in 0..num_records {
+        match reader.next_record() {
+            Some(record) => {
+                for &qual in record?.qual() {
+                    min = min.min(qual);
+                    max = max.max(qual);
+                }
+            }
+            _ => break,
+        }
+    }
+
+    if min == u8::MAX {
+        Ok(None)
+    } else if min < b';' {
+        Ok(Some(33))
+    } else if max > b'K' {
+        Ok(Some(64))
+    } else {
+        Ok(None)
+    }
+}
+
+// --------------------------------------------------
+/// Applies `f` to each item using up to `num_threads` threads, keeping
+/// the order of the items
+fn par_map<T, R, F>(items: &[T], num_threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next_item = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..num_threads.max(1).min(items.len()) {
+            let tx = tx.clone();
+            let (next_item, f) = (&next_item, &f);
+            scope.spawn(move || loop {
+                let i = next_item.fetch_add(1, Ordering::SeqCst);
+                if i >= items.len() || tx.send((i, f(&items[i]))).is_err() {
+                    break;
+                }
+            });
+        }
+    });
+    drop(tx);
+
+    let mut results: Vec<(usize, R)> = rx.into_iter().collect();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+// --------------------------------------------------
+/// Prints the plan or writes it to `out_file` as text, a shell script
+/// that can be run instead, or JSON
+fn write_plan(
+    plan: &Plan,
+    format: PlanFormat,
+    out_file: &Option<PathBuf>,
+) -> MyResult<()> {
+    let mut out: Box<dyn Write> = match out_file {
+        Some(path) => Box::new(File::create(path)?),
+        _ => Box::new(std::io::stdout()),
+    };
+
+    match format {
+        PlanFormat::Text => {
+            writeln!(
+                out,
+                "Plan: {} job{}, {} skipped",
+                plan.jobs.len(),
+                if plan.jobs.len() == 1 { "" } else { "s" },
+                plan.skipped.len()
+            )?;
+            for concat in &plan.concats {
+                writeln!(
+                    out,
+                    "{}\tcat {} > {}",
+                    concat.sample,
+                    concat.sources.join(" "),
+                    concat.target.display()
+                )?;
+            }
+            for job in &plan.jobs {
+                if let Some(source) = &job.interleaved {
+                    writeln!(
+                        out,
+                        "{}\tdeinterleave {} > {} {}",
+                        job.sample,
+                        source.display(),
+                        job.forward.display(),
+                        job.reverse.display()
+                    )?;
+                }
+                writeln!(out, "{}\t{}", job.sample, job)?;
+            }
+            for sample in &plan.skipped {
+                writeln!(out, "{}\tskipped", sample)?;
+            }
+            for (sample, reason) in &plan.rejected {
+                writeln!(out, "{}\trejected: {}", sample, reason)?;
+            }
+        }
+        PlanFormat::Shell => {
+            writeln!(out, "#!/bin/sh")?;
+            writeln!(out, "# run_pear plan: {} jobs", plan.jobs.len())?;
+            for sample in &plan.skipped {
+                writeln!(out, "# skipped {}", sample)?;
+            }
+            for (sample, reason) in &plan.rejected {
+                writeln!(out, "# rejected {}: {}", sample, reason)?;
+            }
+            for job in &plan.jobs {
+                let dir = job
+                    .out_prefix
+                    .parent()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| ".".to_string());
+                let cats: Vec<(String, String)> = plan
+                    .concats
+                    .iter()
+                    .filter(|c| c.sample == job.sample)
+                    .map(|c| {
+                        let sources: Vec<String> =
+                            c.sources.iter().map(|s| shell_quote(s)).collect();
+                        let target =
+                            shell_quote(&c.target.display().to_string());
+                        let lanes = c
+                            .target
+                            .parent()
+                            .map(|p| p.display().to_string())
+                            .unwrap_or_else(|| ".".to_string());
+                        (
+                            format!(
+                                "mkdir -p {} && cat {} > {} && ",
+                                shell_quote(&lanes),
+                                sources.join(" "),
+                                target
+                            ),
+                            target,
+                        )
+                    })
+                    .collect();
+                let (cats, targets): (Vec<String>, Vec<String>) =
+                    cats.into_iter().unzip();
+                let (split, cleanup) = match &job.interleaved {
+                    Some(source) => {
+                        let (fwd, rev) = (
+                            shell_quote(&job.forward.display().to_string()),
+                            shell_quote(&job.reverse.display().to_string()),
+                        );
+                        (
+                            format!(
+                                "{} {} | paste - - - - - - - - | \
+                                 awk -F '\\t' -v r1={} -v r2={} \
+                                 '{{print $1\"\\n\"$2\"\\n\"$3\"\\n\"$4 > r1; \
+                                 print $5\"\\n\"$6\"\\n\"$7\"\\n\"$8 > r2}}' && ",
+                                decompress_command(source),
+                                shell_quote(&source.display().to_string()),
+                                fwd,
+                                rev
+                            ),
+                            format!(" && rm -f {} {}", fwd, rev),
+                        )
+                    }
+                    _ if !targets.is_empty() => (
+                        String::new(),
+                        format!(" && rm -f {}", targets.join(" ")),
+                    ),
+                    _ => (String::new(), String::new()),
+                };
+                // The native engine has no command to run from a script
+                if job.engine == Engine::Native {
+                    write!(out, "# ")?;
+                }
+                writeln!(
+                    out,
+                    "mkdir -p {} && {}{}{}{}",
+                    shell_quote(&dir),
+                    cats.join(""),
+                    split,
+                    job,
+                    cleanup
+                )?;
+            }
+        }
+        PlanFormat::Json => {
+            let jobs: Vec<serde_json::Value> = plan
+                .jobs
+                .iter()
+                .map(|job| {
+                    serde_json::json!({
+                        "sample": job.sample,
+                        "merger": match job.engine {
+                            Engine::External => job.merger.name(),
+                            Engine::Native => "native",
+                        },
+                        "forward": job.forward,
+                        "reverse": job.reverse,
+                        "out_prefix": job.out_prefix,
+                        "argv": std::iter::once(job.program.to_string())
+                            .chain(job.args().iter().map(|a| {
+                                a.to_string_lossy().to_string()
+                            }))
+                            .collect::<Vec<String>>(),
+                        "command": job.to_string(),
+                        "interleaved": job.interleaved,
+                        "concat": plan
+                            .concats
+                            .iter()
+                            .filter(|c| c.sample == job.sample)
+                            .map(|c| serde_json::json!({
+                                "sources": c.sources,
+                                "target": c.target,
+                            }))
+                            .collect::<Vec<serde_json::Value>>(),
+                    })
+                })
+                .collect();
+
+            let json = serde_json::json!({
+                "jobs": jobs,
+                "skipped": plan.skipped,
+                "rejected": plan
+                    .rejected
+                    .iter()
+                    .map(|(sample, reason)| serde_json::json!({
+                        "sample": sample,
+                        "reason": reason,
+                    }))
+                    .collect::<Vec<serde_json::Value>>(),
+            });
+            writeln!(out, "{}", serde_json::to_string_pretty(&json)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Points the pairs of multi-lane samples at the temporary files the
+/// lanes will be concatenated into,
+/// "<out_dir>/.run_pear/lanes/<sample>/<sample>_R[12].<ext>"
+fn plan_concats(
+    classified: &Classified,
+    out_dir: &Path,
+    pairs: &mut ReadPairLookup,
+) -> Vec<Concat> {
+    let mut concats = vec![];
+    let mut samples: Vec<&String> = classified.lane_files.keys().collect();
+    samples.sort();
+
+    for sample in samples {
+        let files = &classified.lane_files[sample];
+        for (direction, read) in &[
+            (ReadDirection::Forward, "R1"),
+            (ReadDirection::Reverse, "R2"),
+        ] {
+            if let (Some(sources), Some(pair)) =
+                (files.get(direction), pairs.get_mut(sample))
+            {
+                let ext = get_extension(Path::new(&sources[0]))
+                    .unwrap_or_else(|| "fastq".to_string());
+                let target = lanes_dir(out_dir)
+                    .join(sample)
+                    .join(format!("{}_{}.{}", sample, read, ext));
+                pair.insert(direction.clone(), target.display().to_string());
+                concats.push(Concat {
+                    sample: sample.to_string(),
+                    sources: sources.clone(),
+                    target,
+                });
+            }
+        }
+    }
+
+    concats
+}
+
+// --------------------------------------------------
+/// Removes a sample's concatenated lane files once its job has ended
+fn remove_concats(concats: &[Concat], sample: &str) {
+    for concat in concats.iter().filter(|c| c.sample == sample) {
+        if concat.target.exists() {
+            if let Err(e) = fs::remove_file(&concat.target) {
+                eprintln!(
+                    "Failed to remove \"{}\": {}",
+                    concat.target.display(),
+                    e
+                );
+            }
+        }
+        if let Some(dir) = concat.target.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+// --------------------------------------------------
+/// Where the lanes of multi-lane samples are concatenated
+fn lanes_dir(out_dir: &Path) -> PathBuf {
+    out_dir.join(state::STATE_DIR).join("lanes")
+}
+
+// --------------------------------------------------
+/// Concatenates the files byte for byte, which is also valid for gzip
+fn concat_files(sources: &[String], target: &Path) -> MyResult<()> {
+    if let Some(dir) = target.parent() {
+        DirBuilder::new().recursive(true).create(dir)?;
+    }
+    let mut out = File::create(target)?;
+    for source in sources {
+        let mut file =
+            File::open(source).map_err(|e| format!("{}: {}", source, e))?;
+        std::io::copy(&mut file, &mut out)?;
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Translates the PEAR settings in the config to typed options
+fn pear_options(config: &Config) -> Vec<PearOption> {
+    let mut options = vec![];
+    if let Some(p_value) = config.p_value {
+        options.push(PearOption::PValue(p_value));
+    }
+
+    if let Some(min_overlap) = config.min_overlap {
+        options.push(PearOption::MinOverlap(min_overlap));
+    }
+
+    if let Some(max_assembly_length) = config.max_assembly_length {
+        options.push(PearOption::MaxAssemblyLength(max_assembly_length));
+    }
+
+    if let Some(min_assembly_length) = config.min_assembly_length {
+        options.push(PearOption::MinAssemblyLength(min_assembly_length));
+    }
+
+    if let Some(min_trim_length) = config.min_trim_length {
+        options.push(PearOption::MinTrimLength(min_trim_length));
+    }
+
+    if let Some(quality_threshold) = config.quality_threshold {
+        options.push(PearOption::QualityThreshold(quality_threshold));
+    }
+
+    if let Some(max_uncalled_base) = config.max_uncalled_base {
+        options.push(PearOption::MaxUncalledBase(max_uncalled_base));
+    }
+
+    if let Some(test_method) = config.test_method {
+        options.push(PearOption::TestMethod(test_method));
+    }
+
+    if config.empirical_freqs.unwrap_or(false) {
+        options.push(PearOption::EmpiricalFreqs);
+    }
+
+    if let Some(score_method) = config.score_method {
+        options.push(PearOption::ScoreMethod(score_method));
+    }
+
+    if let Some(phred_base) = config.phred_base {
+        options.push(PearOption::PhredBase(phred_base));
+    }
+
+    if let Some(memory) = &config.memory {
+        options.push(PearOption::Memory(memory.to_string()));
+    }
+
+    if let Some(cap) = config.cap {
+        options.push(PearOption::Cap(cap));
+    }
+
+    if let Some(threads) = config.threads {
+        options.push(PearOption::Threads(threads));
+    }
+
+    if config.nbase.unwrap_or(false) {
+        options.push(PearOption::Nbase);
+    }
+
+    if config.keep_original.unwrap_or(false) {
+        options.push(PearOption::KeepOriginal);
+    }
+
+    if config.stitch.unwrap_or(false) {
+        options.push(PearOption::Stitch);
+    }
+
+    if let Some(stitch_spacer) = config.stitch_spacer {
+        options.push(PearOption::StitchSpacer(stitch_spacer));
+    }
+
+    options
+}
+
+// --------------------------------------------------
+/// Overrides or adds the option named like the command-line argument,
+/// e.g., "p_value" or "stitch" (for flags "true"/"false", "yes"/"no" or
+/// "1"/"0"). An empty value leaves the options unchanged.
+fn set_pear_option(
+    options: &mut Vec<PearOption>,
+    name: &str,
+    value: &str,
+) -> MyResult<()> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    let bad = |e: &dyn fmt::Display| -> Box<dyn Error> {
+        From::from(format!("Bad value \"{}\" for \"{}\": {}", value, name, e))
+    };
+    let int = || value.parse::<u32>().map_err(|e| bad(&e));
+    let float = || value.parse::<f32>().map_err(|e| bad(&e));
+    let flag = || match value.to_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(bad(&"expected true or false")),
+    };
+
+    let (option, enabled) = match name {
+        "p_value" => (PearOption::PValue(float()?), true),
+        "min_overlap" => (PearOption::MinOverlap(int()?), true),
+        "max_assembly_length" => (PearOption::MaxAssemblyLength(int()?), true),
+        "min_assembly_length" => (PearOption::MinAssemblyLength(int()?), true),
+        "min_trim_length" => (PearOption::MinTrimLength(int()?), true),
+        "quality_threshold" => (PearOption::QualityThreshold(int()?), true),
+        "max_uncalled_base" => (PearOption::MaxUncalledBase(float()?), true),
+        "test_method" => (PearOption::TestMethod(int()?), true),
+        "empirical_freqs" => (PearOption::EmpiricalFreqs, flag()?),
+        "score_method" => (PearOption::ScoreMethod(int()?), true),
+        "phred_base" => (PearOption::PhredBase(int()?), true),
+        "memory" => (PearOption::Memory(value.to_string()), true),
+        "cap" => (PearOption::Cap(int()?), true),
+        "threads" => (PearOption::Threads(int()?), true),
+        "nbase" => (PearOption::Nbase, flag()?),
+        "keep_original" => (PearOption::KeepOriginal, flag()?),
+        "stitch" => (PearOption::Stitch, flag()?),
+        "stitch_spacer" => (PearOption::StitchSpacer(int()?), true),
+        _ => return Err(From::from(format!("Unknown option \"{}\"", name))),
+    };
+
+    let kind = std::mem::discriminant(&option);
+    options.retain(|opt| std::mem::discriminant(opt) != kind);
+    if enabled {
+        options.push(option);
+    }
+
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Reads the pairs from a TSV (or CSV if the name ends in ".csv") with
+/// the columns "sample", "forward" and "reverse" plus any pear options
+/// named like the command-line arguments. Relative paths are relative
+/// to the manifest.
+fn read_manifest(path: &Path) -> MyResult<(Classified, SampleParams)> {
+    let is_csv = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(if is_csv { b',' } else { b'\t' })
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_path(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let headers: Vec<String> =
+        reader.headers()?.iter().map(|h| h.to_lowercase()).collect();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let (sample_col, fwd_col, rev_col) =
+        match (column("sample"), column("forward"), column("reverse")) {
+            (Some(s), Some(f), Some(r)) => (s, f, r),
+            _ => {
+                let msg = format!(
+                    "{}: needs \"sample\", \"forward\" and \"reverse\" columns",
+                    path.display()
+                );
+                return Err(From::from(msg));
+            }
+        };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut pairs: ReadPairLookup = HashMap::new();
+    let mut params: SampleParams = HashMap::new();
+    let mut patterns: HashMap<String, String> = HashMap::new();
+    let mut errors: Vec<String> = vec![];
+
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let line = i + 2;
+        let field = |col: usize| record.get(col).unwrap_or("").to_string();
+        let sample = field(sample_col);
+
+        if sample.is_empty() {
+            errors.push(format!("line {}: missing sample name", line));
+            continue;
+        }
+
+        // The name is used for the sample's directory in the output
+        if sample.contains('/') || sample.starts_with('.') {
+            errors.push(format!(
+                "line {}: sample \"{}\" cannot contain \"/\" or start \
+                 with \".\"",
+                line, sample
+            ));
+            continue;
+        }
+
+        if pairs.contains_key(&sample) {
+            errors.push(format!(
+                "line {}: duplicate sample \"{}\"",
+                line, sample
+            ));
+            continue;
+        }
+
+        let mut pair: ReadPair = HashMap::new();
+        for (direction, col) in &[
+            (ReadDirection::Forward, fwd_col),
+            (ReadDirection::Reverse, rev_col),
+        ] {
+            let file = field(*col);
+            if file.is_empty() {
+                errors.push(format!(
+                    "line {}: missing {:?} file for \"{}\"",
+                    line, direction, sample
+                ));
+                continue;
+            }
+
+            let file = dir.join(file);
+            if !file.is_file() {
+                errors.push(format!(
+                    "line {}: \"{}\" does not exist",
+                    line,
+                    file.display()
+                ));
+            }
+            pair.insert(direction.clone(), file.display().to_string());
+        }
+
+        let mut sample_params = vec![];
+        for (col, name) in headers.iter().enumerate() {
+            if col == sample_col || col == fwd_col || col == rev_col {
+                continue;
+            }
+
+            let value = field(col);
+            if let Err(e) = set_pear_option(&mut vec![], name, &value) {
+                errors.push(format!("line {}: {}", line, e));
+            }
+            sample_params.push((name.to_string(), value));
+        }
+
+        patterns.insert(sample.to_string(), "manifest".to_string());
+        params.insert(sample.to_string(), sample_params);
+        pairs.insert(sample, pair);
+    }
+
+    if !errors.is_empty() {
+        let msg = format!("{}:\n{}", path.display(), errors.join("\n"));
+        return Err(From::from(msg));
+    }
+
+    if pairs.is_empty() {
+        let msg = format!("{}: no samples", path.display());
+        return Err(From::from(msg));
+    }
+
+    let classified = Classified {
+        pairs,
+        unpaired: HashMap::new(),
+        unmatched: vec![],
+        patterns,
+        illumina: HashMap::new(),
+        lane_files: HashMap::new(),
+    };
+
+    Ok((classified, params))
+}
+
+// --------------------------------------------------
+/// Compiles the search settings, skipping the output directory
+fn find_options(config: &Config) -> MyResult<FindOptions> {
+    let compile = |globs: &[String]| -> MyResult<Vec<glob::Pattern>> {
+        globs
+            .iter()
+            .map(|g| {
+                glob::Pattern::new(g).map_err(|e| {
+                    From::from(format!("Bad glob \"{}\": {}", g, e))
+                })
+            })
+            .collect()
+    };
+
+    Ok(FindOptions {
+        recursive: config.recursive.unwrap_or(false),
+        max_depth: config.max_depth,
+        include: compile(&config.include)?,
+        exclude: compile(&config.exclude)?,
+        follow_links: config.follow_links.unwrap_or(false),
+        hidden: config.hidden.unwrap_or(false),
+        skip_dir: fs::canonicalize(&config.out_dir).ok(),
+    })
+}
+
+// --------------------------------------------------
+/// Files given directly are always used; directories are searched
+/// (recursively if requested) for files passing the filters
+fn find_files(
+    paths: &[String],
+    opts: &FindOptions,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let matches = |patterns: &[glob::Pattern], path: &Path| {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        patterns
+            .iter()
+            .any(|p| p.matches(&name) || p.matches_path(path))
+    };
+
+    let mut files = vec![];
+    for path in paths {
+        let meta = fs::metadata(path)?;
+        if meta.is_file() {
+            files.push(path.to_owned());
+            continue;
+        }
+
+        let max_depth = if opts.recursive {
+            opts.max_depth.unwrap_or(usize::MAX).max(1)
+        } else {
+            1
+        };
+
+        let walker = WalkDir::new(path)
+            .min_depth(1)
+            .max_depth(max_depth)
+            .follow_links(opts.follow_links)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                let is_hidden =
+                    entry.file_name().to_string_lossy().starts_with('.');
+                let is_skipped = opts.skip_dir.as_ref().is_some_and(|dir| {
+                    entry.file_type().is_dir()
+                        && fs::canonicalize(entry.path())
+                            .is_ok_and(|p| &p == dir)
+                });
+                (opts.hidden || !is_hidden)
+                    && !is_skipped
+                    && !matches(&opts.exclude, entry.path())
+            });
+
+        for entry in walker {
+            let entry = entry?;
+            let is_file = if entry.path_is_symlink() && !opts.follow_links {
+                fs::metadata(entry.path()).is_ok_and(|m| m.is_file())
+            } else {
+                entry.file_type().is_file()
+            };
+
+            if is_file
+                && (opts.include.is_empty()
+                    || matches(&opts.include, entry.path()))
+            {
+                files.push(entry.path().display().to_string());
+            }
+        }
+    }
 
     if files.is_empty() {
         return Err(From::from("No input files"));
     }
 
-    Ok(files)
+    Ok(files)
+}
+
+// --------------------------------------------------
+/// Groups the files into pairs by sample name. Illumina files have the
+/// sample sheet index and lane removed from the sample name and kept in
+/// `illumina`. A sample read on several lanes is split into one sample
+/// per lane (e.g., "Foo_L001") unless `merge_lanes`.
+fn classify(
+    paths: &[String],
+    pair_patterns: &[String],
+    merge_lanes: bool,
+) -> Result<Classified, Box<dyn Error>> {
+    let paths = paths.iter().map(Path::new);
+    let mut exts: Vec<String> =
+        paths.clone().filter_map(get_extension).collect();
+    exts.dedup();
+
+    let patterns = make_pair_patterns(pair_patterns, &exts)?;
+
+    let mut reads: ReadPairLookup = HashMap::new();
+    let mut unmatched: Vec<String> = vec![];
+    let mut matched_by: HashMap<String, String> = HashMap::new();
+    let mut illumina: HashMap<String, IlluminaMeta> = HashMap::new();
+    let mut lanes: HashMap<String, Vec<(String, ReadDirection, String)>> =
+        HashMap::new();
+    for path in paths.map(Path::new) {
+        let path_str = path.to_str().expect("Convert path");
+
+        if let Some(file_name) = path.file_name() {
+            let basename = file_name.to_string_lossy();
+            let found = patterns.iter().find_map(|pattern| {
+                pattern.regex.captures(&basename).map(|cap| (pattern, cap))
+            });
+
+            if let Some((pattern, cap)) = found {
+                let lane = cap.name("lane").map(|m| m.as_str().to_string());
+                let sample_name = &cap["sample"].to_string();
+                let direction = match cap.name("read") {
+                    Some(read) if read.as_str().ends_with('1') => {
+                        ReadDirection::Forward
+                    }
+                    _ => ReadDirection::Reverse,
+                };
+
+                matched_by
+                    .entry(sample_name.to_string())
+                    .or_insert_with(|| pattern.name.to_string());
+
+                if let Some(index) = cap.name("index") {
+                    let meta =
+                        illumina.entry(sample_name.to_string()).or_default();
+                    meta.index = Some(index.as_str().to_string());
+                    if let Some(lane) = &lane {
+                        if !meta.lanes.contains(lane) {
+                            meta.lanes.push(lane.to_string());
+                            meta.lanes.sort();
+                        }
+                        lanes.entry(sample_name.to_string()).or_default().push(
+                            (
+                                lane.to_string(),
+                                direction.clone(),
+                                path_str.to_string(),
+                            ),
+                        );
+                    }
+                }
+
+                // Lanes of one sample share a direction until merged below
+                let pair = reads.entry(sample_name.to_string()).or_default();
+                match pair.get(&direction) {
+                    Some(other) if lane.is_none() => {
+                        let msg = format!(
+                            "Sample \"{}\" has two {} files: \"{}\" and \"{}\"",
+                            sample_name,
+                            if direction == ReadDirection::Forward {
+                                "R1"
+                            } else {
+                                "R2"
+                            },
+                            other,
+                            path_str
+                        );
+                        return Err(From::from(msg));
+                    }
+                    _ => {
+                        pair.insert(direction, path_str.to_string());
+                    }
+                }
+                continue;
+            }
+        }
+
+        unmatched.push(path_str.to_string());
+    }
+
+    // Samples with several lanes read from the sorted lane files
+    let mut lane_files: HashMap<String, LaneFiles> = HashMap::new();
+    for (sample, mut files) in lanes {
+        files.sort();
+        let lanes_for = |dir: ReadDirection| -> Vec<String> {
+            files
+                .iter()
+                .filter(|(_, d, _)| *d == dir)
+                .map(|(lane, _, _)| lane.to_string())
+                .collect()
+        };
+        let fwd_lanes = lanes_for(ReadDirection::Forward);
+        let rev_lanes = lanes_for(ReadDirection::Reverse);
+
+        if fwd_lanes.len() < 2 && rev_lanes.len() < 2 {
+            continue;
+        }
+
+        if !merge_lanes {
+            reads.remove(&sample);
+            let pattern = matched_by.remove(&sample);
+            let meta = illumina.remove(&sample).unwrap_or_default();
+            for (lane, direction, file) in files {
+                let name = format!("{}_L{}", sample, lane);
+                reads
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(direction, file);
+                if let Some(pattern) = &pattern {
+                    matched_by.insert(name.clone(), pattern.to_string());
+                }
+                illumina.insert(
+                    name,
+                    IlluminaMeta {
+                        index: meta.index.clone(),
+                        lanes: vec![lane],
+                    },
+                );
+            }
+            continue;
+        }
+
+        if fwd_lanes != rev_lanes {
+            let msg = format!(
+                "Sample \"{}\" has R1 lanes {} but R2 lanes {}",
+                sample,
+                fwd_lanes.join(", "),
+                rev_lanes.join(", ")
+            );
+            return Err(From::from(msg));
+        }
+
+        let mut by_dir: LaneFiles = HashMap::new();
+        for (_, direction, file) in files {
+            by_dir.entry(direction).or_default().push(file);
+        }
+
+        if let Some(pair) = reads.get_mut(&sample) {
+            for (direction, files) in &by_dir {
+                pair.insert(direction.clone(), files[0].to_string());
+            }
+        }
+        lane_files.insert(sample, by_dir);
+    }
+
+    let bad: Vec<String> = reads
+        .iter()
+        .filter_map(|(k, v)| {
+            if !v.contains_key(&ReadDirection::Forward)
+                || !v.contains_key(&ReadDirection::Reverse)
+            {
+                Some(k.to_string())
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let mut unpaired: ReadPairLookup = HashMap::new();
+    for key in bad {
+        if let Some(pair) = reads.remove(&key) {
+            unpaired.insert(key, pair);
+        }
+    }
+
+    if reads.is_empty() {
+        Err(From::from("No pairs"))
+    } else {
+        Ok(Classified {
+            pairs: reads,
+            unpaired,
+            unmatched,
+            patterns: matched_by,
+            illumina,
+            lane_files,
+        })
+    }
+}
+
+// --------------------------------------------------
+/// Compiles the user's patterns (regexes or preset names) or, if none
+/// were given, all the presets followed by the generic pattern built
+/// from the file extensions
+fn make_pair_patterns(
+    specs: &[String],
+    exts: &[String],
+) -> MyResult<Vec<PairPattern>> {
+    let dots = Regex::new(r"\.").unwrap();
+    let exts: Vec<String> = exts
+        .iter()
+        .map(|x| dots.replace_all(x, r"\.").to_string())
+        .collect();
+    let generic = format!(
+        r"^(?P<sample>.+)[_-][Rr]?(?P<read>[12])?\.(?:{})$",
+        exts.join("|")
+    );
+
+    let preset = |name: &str| -> Option<String> {
+        if name == "generic" {
+            Some(generic.to_string())
+        } else {
+            PAIR_PRESETS
+                .iter()
+                .find(|(preset, _)| *preset == name)
+                .map(|(_, regex)| regex.to_string())
+        }
+    };
+
+    let specs: Vec<String> = if specs.is_empty() {
+        PAIR_PRESETS
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(std::iter::once("generic".to_string()))
+            .collect()
+    } else {
+        specs.to_vec()
+    };
+
+    let mut patterns = vec![];
+    for spec in specs {
+        let (name, regex) = match preset(&spec) {
+            Some(regex) => (spec, regex),
+            _ => (spec.to_string(), spec),
+        };
+
+        let regex = Regex::new(&regex)
+            .map_err(|e| format!("Bad pair pattern \"{}\": {}", name, e))?;
+
+        let groups: Vec<&str> = regex.capture_names().flatten().collect();
+        if !groups.contains(&"sample") || !groups.contains(&"read") {
+            let msg = format!(
+                "Pair pattern \"{}\" needs \"sample\" and \"read\" groups",
+                name
+            );
+            return Err(From::from(msg));
+        }
+
+        patterns.push(PairPattern { name, regex });
+    }
+
+    Ok(patterns)
+}
+
+// --------------------------------------------------
+/// Treats each file as one interleaved sample named for the file minus
+/// its extension
+fn classify_interleaved(paths: &[String]) -> MyResult<Classified> {
+    let mut pairs: ReadPairLookup = HashMap::new();
+    let mut patterns: HashMap<String, String> = HashMap::new();
+
+    for path in paths {
+        let basename = Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let sample = match get_extension(Path::new(path)) {
+            Some(ext) => basename.trim_end_matches(&format!(".{}", ext)),
+            _ => &basename,
+        }
+        .to_string();
+
+        if pairs.contains_key(&sample) {
+            let msg = format!(
+                "Sample \"{}\" from \"{}\" is not unique",
+                sample, path
+            );
+            return Err(From::from(msg));
+        }
+
+        let mut pair: ReadPair = HashMap::new();
+        pair.insert(ReadDirection::Forward, path.to_string());
+        pair.insert(ReadDirection::Reverse, path.to_string());
+        patterns.insert(sample.to_string(), "interleaved".to_string());
+        pairs.insert(sample, pair);
+    }
+
+    if pairs.is_empty() {
+        return Err(From::from("No pairs"));
+    }
+
+    Ok(Classified {
+        pairs,
+        unpaired: HashMap::new(),
+        unmatched: vec![],
+        patterns,
+        illumina: HashMap::new(),
+        lane_files: HashMap::new(),
+    })
+}
+
+// --------------------------------------------------
+/// Writes alternating FASTQ records to the forward and reverse files.
+/// Temporary files rather than FIFOs are used as pear reads its input
+/// twice when computing the empirical base frequencies.
+fn deinterleave(source: &Path, forward: &Path, reverse: &Path) -> MyResult<()> {
+    let mut input = fastq::open(source)?;
+    let mut outs = [
+        fastq::Writer::new(BufWriter::new(File::create(forward)?)),
+        fastq::Writer::new(BufWriter::new(File::create(reverse)?)),
+    ];
+
+    let mut num_records = 0;
+    while let Some(record) = input.next_record() {
+        outs[num_records % 2].write(&record?)?;
+        num_records += 1;
+    }
+
+    if num_records % 2 != 0 {
+        let msg = format!(
+            "{}: odd number of records ({}), not interleaved",
+            source.display(),
+            num_records
+        );
+        return Err(From::from(msg));
+    }
+
+    for out in outs.iter_mut() {
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Warns about samples missing a mate and files that did not match the
+/// naming pattern, or fails if `strict`
+fn check_classified(classified: &Classified, strict: bool) -> MyResult<()> {
+    let mut unpaired: Vec<(&String, &ReadPair)> =
+        classified.unpaired.iter().collect();
+    unpaired.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut problems = vec![];
+    for (sample, pair) in unpaired {
+        for (found, missing) in &[
+            (ReadDirection::Forward, "reverse"),
+            (ReadDirection::Reverse, "forward"),
+        ] {
+            if let Some(file) = pair.get(found) {
+                problems.push(format!(
+                    "Sample \"{}\" is missing the {} read (found \"{}\")",
+                    sample, missing, file
+                ));
+            }
+        }
+    }
+
+    for file in &classified.unmatched {
+        problems.push(format!("\"{}\" does not look like R1/2", file));
+    }
+
+    if strict && !problems.is_empty() {
+        return Err(From::from(problems.join("\n")));
+    }
+
+    for problem in problems {
+        eprintln!("Warning: {}", problem);
+    }
+
+    Ok(())
+}
+
+// --------------------------------------------------
+/// The shell command that writes a file decompressed to STDOUT, chosen
+/// by its extension. `gzip -f` passes uncompressed input through.
+fn decompress_command(path: &Path) -> &'static str {
+    match fastq::Compression::from_path(path) {
+        fastq::Compression::Bzip2 => "bzip2 -dc",
+        fastq::Compression::Zstd => "zstd -dc",
+        _ => "gzip -cdf",
+    }
+}
+
+// --------------------------------------------------
+/// Returns the extension plus optional ".gz"
+fn get_extension(path: &Path) -> Option<String> {
+    let re = Regex::new(r"\.([^.]+(?:\.gz)?)$").unwrap();
+    if let Some(basename) = path.file_name() {
+        let basename = basename.to_string_lossy();
+        if let Some(cap) = re.captures(&basename) {
+            return Some(cap[1].to_string());
+        }
+    }
+    None
+}
+
+// --------------------------------------------------
+/// Runs the jobs on a pool of `num_concurrent_jobs` workers. Once
+/// `num_halt` jobs have failed (0 means never), no new jobs are started
+/// but those already running are allowed to finish, like
+/// `parallel --halt soon,fail=N`. Only a job failing all its attempts
+/// under `retry` counts, and not one that timed out. A SIGINT or SIGTERM
+/// kills the running jobs and starts no more. `on_result` is called
+/// with each job as it finishes.
+fn run_jobs(
+    jobs: &[PearJob],
+    msg: &str,
+    num_concurrent_jobs: u32,
+    num_halt: u32,
+    retry: &RetryPolicy,
+    mut on_result: impl FnMut(&PearJob, &JobResult),
+) -> MyResult<Vec<JobResult>> {
+    let num_jobs = jobs.len();
+
+    if num_jobs == 0 {
+        return Ok(vec![]);
+    }
+
+    println!(
+        "{} (# {} job{} @ {})",
+        msg,
+        num_jobs,
+        if num_jobs == 1 { "" } else { "s" },
+        num_concurrent_jobs,
+    );
+
+    let num_workers = (num_concurrent_jobs.max(1) as usize).min(num_jobs);
+    let next_job = AtomicUsize::new(0);
+    let num_failed = AtomicUsize::new(0);
+    let halted = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+
+    let mut results: Vec<JobResult> = jobs
+        .iter()
+        .map(|job| JobResult {
+            sample: job.sample.to_string(),
+            status: JobStatus::Halted,
+            stats: None,
+            elapsed: None,
+            attempts: vec![],
+        })
+        .collect();
+
+    thread::scope(|scope| {
+        for _ in 0..num_workers {
+            let tx = tx.clone();
+            let (next_job, num_failed, halted) =
+                (&next_job, &num_failed, &halted);
+            scope.spawn(move || loop {
+                if halted.load(Ordering::SeqCst) || interrupted() {
+                    break;
+                }
+
+                let i = next_job.fetch_add(1, Ordering::SeqCst);
+                if i >= num_jobs {
+                    break;
+                }
+
+                let start = Instant::now();
+                let (status, stats, attempts) = run_attempts(&jobs[i], retry);
+                let elapsed = start.elapsed();
+                let counts = !matches!(
+                    status,
+                    JobStatus::Success
+                        | JobStatus::TimedOut(_)
+                        | JobStatus::Interrupted
+                );
+                if counts {
+                    let failed = num_failed.fetch_add(1, Ordering::SeqCst) + 1;
+                    if num_halt > 0 && failed >= num_halt as usize {
+                        halted.store(true, Ordering::SeqCst);
+                    }
+                }
+
+                if tx.send((i, (status, stats, elapsed, attempts))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        for (i, (status, stats, elapsed, attempts)) in rx {
+            results[i].status = status;
+            results[i].stats = stats;
+            results[i].elapsed = Some(elapsed);
+            results[i].attempts = attempts;
+            on_result(&jobs[i], &results[i]);
+        }
+    });
+
+    for (job, result) in jobs.iter().zip(&results) {
+        if result.status == JobStatus::Halted {
+            on_result(job, result);
+        }
+    }
+
+    Ok(results)
 }
 
 // --------------------------------------------------
-fn classify(paths: &[String]) -> Result<ReadPairLookup, Box<dyn Error>> {
-    let paths = paths.iter().map(Path::new);
-    let mut exts: Vec<String> =
-        paths.clone().map(get_extension).filter_map(|x| x).collect();
-    exts.dedup();
+/// Runs a job until it succeeds or has used all its retries
+fn run_attempts(
+    job: &PearJob,
+    retry: &RetryPolicy,
+) -> (JobStatus, Option<MergeStats>, Vec<Attempt>) {
+    let mut attempts = vec![];
+    let mut attempt = 0;
+    loop {
+        let job = retry.job(job, attempt);
+        let start = Instant::now();
+        let (status, stats) = run_job(&job, attempt);
+        attempts.push(Attempt {
+            status: status.to_string(),
+            seconds: start.elapsed().as_secs_f64(),
+            memory: job.options.iter().find_map(|opt| match opt {
+                PearOption::Memory(memory) => Some(memory.to_string()),
+                _ => None,
+            }),
+            threads: job.options.iter().find_map(|opt| match opt {
+                PearOption::Threads(threads) => Some(*threads),
+                _ => None,
+            }),
+        });
 
-    let dots = Regex::new(r"\.").unwrap();
-    let exts: Vec<String> = exts
+        // Errors such as a missing merger would only happen again
+        let retryable = matches!(
+            status,
+            JobStatus::Failed(_)
+                | JobStatus::Invalid(_)
+                | JobStatus::TimedOut(_)
+        );
+        if !retryable || attempt >= retry.retries || interrupted() {
+            return (status, stats, attempts);
+        }
+
+        attempt += 1;
+        let delay = retry.delay(attempt);
+        eprintln!(
+            "Retrying {} in {}s (attempt {} of {}): {}",
+            job.sample,
+            delay.as_secs(),
+            attempt + 1,
+            retry.retries + 1,
+            status
+        );
+        let start = Instant::now();
+        while start.elapsed() < delay && !interrupted() {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+/// The longest the doubling wait between attempts grows to
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+impl RetryPolicy {
+    /// The job for an attempt, counting from 0
+    fn job(&self, job: &PearJob, attempt: u32) -> PearJob {
+        let factor = 2u32.saturating_pow(attempt);
+        let options = job
+            .options
+            .iter()
+            .map(|opt| match opt {
+                PearOption::Memory(memory) if self.more_memory => {
+                    PearOption::Memory(scale_memory(memory, factor))
+                }
+                PearOption::Threads(threads) if self.fewer_threads => {
+                    PearOption::Threads((threads / factor).max(1))
+                }
+                _ => opt.clone(),
+            })
+            .collect();
+        PearJob {
+            options,
+            ..job.clone()
+        }
+    }
+
+    /// The wait before an attempt, counting from 0, doubling up to
+    /// `MAX_BACKOFF` unless `delay` itself is longer
+    fn delay(&self, attempt: u32) -> Duration {
+        match attempt {
+            0 => Duration::from_secs(0),
+            _ => self
+                .delay
+                .checked_mul(2u32.saturating_pow(attempt - 1))
+                .map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF))
+                .max(self.delay),
+        }
+    }
+}
+
+// --------------------------------------------------
+/// Multiplies a memory size like "200M" or "4G" by a factor, keeping the
+/// unit. Sizes that are not a whole number are returned unchanged.
+fn scale_memory(memory: &str, factor: u32) -> String {
+    let digits = memory.trim_end_matches(|c: char| !c.is_ascii_digit());
+    match digits.parse::<u64>() {
+        Ok(size) => format!(
+            "{}{}",
+            size.saturating_mul(factor as u64),
+            &memory[digits.len()..]
+        ),
+        _ => memory.to_string(),
+    }
+}
+
+// --------------------------------------------------
+/// Runs one job and writes its logs
+fn run_job(job: &PearJob, attempt: u32) -> (JobStatus, Option<MergeStats>) {
+    let (status, stats, captured) = run_staged(job);
+    if let Some(prefix) = &job.log_prefix {
+        if let Err(e) = write_logs(prefix, job, attempt, &status, &captured) {
+            eprintln!("Failed to write the logs of {}: {}", job.sample, e);
+        }
+    }
+    (status, stats)
+}
+
+// --------------------------------------------------
+/// Runs one job, capturing its output to parse the merge statistics. A
+/// staged job writes into its staging directory, and its outputs are
+/// moved into place only if it succeeds and they pass `Outputs::check`.
+fn run_staged(job: &PearJob) -> (JobStatus, Option<MergeStats>, Captured) {
+    if let Some(source) = &job.interleaved {
+        if let Err(e) = deinterleave(source, &job.forward, &job.reverse) {
+            remove_split_files(job);
+            let status = JobStatus::Error(e.to_string());
+            return (status, None, Captured::default());
+        }
+    }
+
+    let staged = match &job.staging {
+        Some(dir) => match make_staging_dir(dir) {
+            Ok(_) => PearJob {
+                out_prefix: dir.join(&job.sample),
+                ..job.clone()
+            },
+            Err(e) => {
+                remove_split_files(job);
+                let status = JobStatus::Error(e.to_string());
+                return (status, None, Captured::default());
+            }
+        },
+        _ => job.clone(),
+    };
+
+    let (mut status, stats, captured) = match job.engine {
+        Engine::External => run_external(&staged),
+        Engine::Native => {
+            let (status, stats) = run_native(&staged);
+            let captured = Captured {
+                command: Some(staged.to_string()),
+                stdout: stats
+                    .as_ref()
+                    .map(|s| s.to_pear_output().into_bytes())
+                    .unwrap_or_default(),
+                stderr: match &status {
+                    JobStatus::Error(e) => format!("{}\n", e).into_bytes(),
+                    _ => vec![],
+                },
+            };
+            (status, stats, captured)
+        }
+    };
+
+    if let Some(dir) = &job.staging {
+        if status == JobStatus::Success {
+            if let Err(e) = job
+                .merger
+                .outputs(&staged.out_prefix)
+                .check(stats.as_ref())
+                .and_then(|_| publish(dir, &job.out_prefix))
+            {
+                status = JobStatus::Invalid(e.to_string());
+            }
+        }
+        // Old outputs that could not be put back are kept for the next run
+        if dir.join(OLD_OUTPUTS).exists() {
+            eprintln!("Keeping the old outputs in \"{}\"", dir.display());
+        } else if let Err(e) = fs::remove_dir_all(dir) {
+            eprintln!("Failed to remove \"{}\": {}", dir.display(), e);
+        }
+    }
+
+    if job.interleaved.is_some() {
+        remove_split_files(job);
+    }
+
+    (status, stats, captured)
+}
+
+// --------------------------------------------------
+/// Writes the captured STDOUT and STDERR to "<prefix>.log" and
+/// "<prefix>.err", each headed by the command line, exit code and
+/// attempt (from 0). Retries are appended after the first attempt.
+/// Native jobs log their statistics as pear would print them.
+fn write_logs(
+    prefix: &Path,
+    job: &PearJob,
+    attempt: u32,
+    status: &JobStatus,
+    captured: &Captured,
+) -> MyResult<()> {
+    let exit_code = match status {
+        JobStatus::Success => "0".to_string(),
+        JobStatus::Failed(Some(code)) => code.to_string(),
+        _ => format!("none, {}", status),
+    };
+    let header = format!(
+        "# command: {}\n# exit code: {}\n# attempt: {}\n",
+        captured.command.clone().unwrap_or_else(|| job.to_string()),
+        exit_code,
+        attempt + 1
+    );
+
+    for (ext, text) in &[("log", &captured.stdout), ("err", &captured.stderr)] {
+        let path = with_extension(prefix, ext);
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(attempt > 0)
+            .truncate(attempt == 0)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        file.write_all(header.as_bytes())?;
+        file.write_all(text)?;
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Appends an extension, e.g., "out/s1/pear" to "out/s1/pear.err"
+fn with_extension(prefix: &Path, ext: &str) -> PathBuf {
+    let mut path = prefix.as_os_str().to_owned();
+    path.push(".");
+    path.push(ext);
+    PathBuf::from(path)
+}
+
+// --------------------------------------------------
+/// The last `num` lines of a log's last attempt, without its header
+fn tail_lines(path: &Path, num: usize) -> Vec<String> {
+    let text = fs::read(path).unwrap_or_default();
+    let text = String::from_utf8_lossy(&text);
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines
+        .iter()
+        .rposition(|line| line.starts_with("# attempt: "))
+        .map_or(0, |i| i + 1);
+    let lines = &lines[start..];
+    lines[lines.len().saturating_sub(num)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// --------------------------------------------------
+/// Creates an empty staging directory
+fn make_staging_dir(dir: &Path) -> MyResult<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    DirBuilder::new()
+        .recursive(true)
+        .create(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    Ok(())
+}
+
+// --------------------------------------------------
+/// The directory in a staging directory holding the outputs being
+/// replaced
+const OLD_OUTPUTS: &str = ".old";
+
+/// Moves the files in a staging directory next to the output prefix,
+/// replacing the outputs of an earlier run. Each file is renamed, so
+/// none is ever seen half-written. The old outputs are moved aside into
+/// the staging directory and only deleted once all the new ones are in
+/// place; if a move fails, the old ones are put back.
+fn publish(staging: &Path, out_prefix: &Path) -> MyResult<()> {
+    let out_dir = out_prefix.parent().unwrap_or_else(|| Path::new("."));
+    DirBuilder::new().recursive(true).create(out_dir)?;
+
+    let new: Vec<PathBuf> = fs::read_dir(staging)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    let old: Vec<PathBuf> = output_files(out_prefix)
         .into_iter()
-        .map(|x| dots.replace(&x, r"\.").to_string())
+        .map(PathBuf::from)
         .collect();
 
-    let pattern = format!(r"(.+)[_-][Rr]?([12])?\.(?:{})$", exts.join("|"));
-    let re = Regex::new(&pattern).unwrap();
+    let aside = staging.join(OLD_OUTPUTS);
+    DirBuilder::new().create(&aside)?;
+    let moved = move_files(&old, &aside)?;
+    if let Err(e) = move_files(&new, out_dir) {
+        match move_files(&moved, out_dir) {
+            Ok(_) => fs::remove_dir(&aside)?,
+            Err(e) => eprintln!("Failed to restore the old outputs: {}", e),
+        }
+        return Err(e);
+    }
+    fs::remove_dir_all(&aside)?;
+    Ok(())
+}
+
+/// Renames the files into a directory, all or none: if one fails, those
+/// already moved are moved back. Returns the new paths.
+fn move_files(files: &[PathBuf], dir: &Path) -> MyResult<Vec<PathBuf>> {
+    let mut moved: Vec<(&PathBuf, PathBuf)> = vec![];
+    for file in files {
+        let target = dir.join(file.file_name().unwrap_or_default());
+        if let Err(e) = fs::rename(file, &target) {
+            for (from, to) in moved.iter().rev() {
+                let _ = fs::rename(to, from);
+            }
+            return Err(From::from(format!("{}: {}", target.display(), e)));
+        }
+        moved.push((file, target));
+    }
+    Ok(moved.into_iter().map(|(_, to)| to).collect())
+}
 
-    let mut reads: ReadPairLookup = HashMap::new();
-    for path in paths.map(Path::new) {
-        let path_str = path.to_str().expect("Convert path");
+/// Puts back the old outputs a publish cut short had moved aside, if
+/// none of the new ones made it into place
+fn restore_outputs(staging: &Path, out_dir: &Path) -> MyResult<()> {
+    let aside = staging.join(OLD_OUTPUTS);
+    let sample = match staging.file_name() {
+        Some(sample) if aside.is_dir() => sample,
+        _ => return Ok(()),
+    };
+    let out_prefix = out_dir.join(sample).join(sample);
+    if output_files(&out_prefix).is_empty() {
+        let old: Vec<PathBuf> = fs::read_dir(&aside)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<_, _>>()?;
+        eprintln!(
+            "Restoring {} output{} of \"{}\"",
+            old.len(),
+            if old.len() == 1 { "" } else { "s" },
+            sample.to_string_lossy()
+        );
+        move_files(&old, &out_dir.join(sample))?;
+    }
+    Ok(())
+}
 
-        if let Some(file_name) = path.file_name() {
-            let basename = file_name.to_string_lossy();
-            if let Some(cap) = re.captures(&basename) {
-                let sample_name = &cap[1];
-                let direction = if &cap[2] == "1" {
-                    ReadDirection::Forward
-                } else {
-                    ReadDirection::Reverse
-                };
+// --------------------------------------------------
+/// Removes the staging and lane directories left by jobs that never
+/// finished, or only reports them on a dry run. Old outputs a cut-short
+/// publish had moved aside are put back first if nothing replaced them.
+fn clean_staging(out_dir: &Path, dry_run: bool) -> MyResult<()> {
+    let staging = out_dir.join(state::STATE_DIR).join("staging");
+    for parent in &[staging, lanes_dir(out_dir)] {
+        if !parent.is_dir() {
+            continue;
+        }
 
-                if !reads.contains_key(sample_name) {
-                    let mut pair: ReadPair = HashMap::new();
-                    pair.insert(direction, path_str.to_string());
-                    reads.insert(sample_name.to_string(), pair);
-                } else if let Some(pair) = reads.get_mut(sample_name) {
-                    pair.insert(direction, path_str.to_string());
+        for entry in fs::read_dir(parent)? {
+            let path = entry?.path();
+            if dry_run {
+                eprintln!("Leftover staging directory \"{}\"", path.display());
+            } else {
+                eprintln!(
+                    "Removing leftover staging directory \"{}\"",
+                    path.display()
+                );
+                restore_outputs(&path, out_dir)?;
+                if path.is_dir() {
+                    fs::remove_dir_all(&path)?;
+                } else {
+                    fs::remove_file(&path)?;
                 }
             }
         }
     }
+    Ok(())
+}
 
-    let bad: Vec<String> = reads
-        .iter()
-        .filter_map(|(k, v)| {
-            if !v.contains_key(&ReadDirection::Forward)
-                || !v.contains_key(&ReadDirection::Reverse)
-            {
-                Some(k.to_string())
-            } else {
-                None
+// --------------------------------------------------
+/// Runs an external merger, reading its statistics from STDOUT or its
+/// output files. The merger runs in its own process group, which is
+/// killed when the job times out or the batch is interrupted.
+fn run_external(job: &PearJob) -> (JobStatus, Option<MergeStats>, Captured) {
+    let mut child = match job
+        .command()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            let status = JobStatus::Error(e.to_string());
+            return (status, None, Captured::default());
+        }
+    };
+
+    // Read the output as it comes so a chatty merger cannot block
+    let read = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
             }
+            buf
         })
-        .collect();
+    };
+    let stdout = read(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read(child.stderr.take().map(|p| Box::new(p) as _));
 
-    for key in bad {
-        reads.remove(&key);
+    let slot = track_group(child.id() as i32);
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break JobStatus::Success,
+            Ok(Some(status)) => break JobStatus::Failed(status.code()),
+            Ok(None) => (),
+            Err(e) => break JobStatus::Error(e.to_string()),
+        }
+
+        if interrupted() {
+            kill_group(&mut child);
+            break JobStatus::Interrupted;
+        }
+
+        if let Some(limit) = job.timeout.filter(|t| start.elapsed() >= *t) {
+            kill_group(&mut child);
+            break JobStatus::TimedOut(limit);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+    untrack_group(slot);
+
+    let captured = Captured {
+        command: Some(job.to_string()),
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    let stats = match status {
+        JobStatus::Success | JobStatus::Failed(_) => job
+            .merger
+            .stats(&String::from_utf8_lossy(&captured.stdout), &job.out_prefix),
+        _ => None,
+    };
+    (status, stats, captured)
+}
+
+// --------------------------------------------------
+/// Kills a child started with `process_group(0)` and everything it
+/// started
+fn kill_group(child: &mut std::process::Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
     }
+    let _ = child.wait();
+}
 
-    if reads.is_empty() {
-        Err(From::from("No pairs"))
-    } else {
-        Ok(reads)
+// --------------------------------------------------
+/// Set by the first SIGINT or SIGTERM. External mergers are killed by
+/// their workers; native merges run to the end of the sample.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The most mergers tracked for the signal handler at once
+const MAX_GROUPS: usize = 1024;
+
+/// The process groups of the running external mergers, 0 for a free
+/// slot. Atomics rather than a lock as the signal handler reads them.
+static GROUPS: [AtomicI32; MAX_GROUPS] =
+    [const { AtomicI32::new(0) }; MAX_GROUPS];
+
+/// Whether a SIGINT or SIGTERM has been received
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Records a running process group, returning its slot if one was free
+fn track_group(pgid: i32) -> Option<usize> {
+    GROUPS.iter().position(|slot| {
+        slot.compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    })
+}
+
+fn untrack_group(slot: Option<usize>) {
+    if let Some(i) = slot {
+        GROUPS[i].store(0, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    // A second signal gives up on a clean stop, but first kills the
+    // mergers, which do not see the terminal's signals in their own
+    // process groups
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        for slot in &GROUPS {
+            let pgid = slot.load(Ordering::SeqCst);
+            if pgid > 0 {
+                unsafe { libc::kill(-pgid, libc::SIGKILL) };
+            }
+        }
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Records SIGINT and SIGTERM for `interrupted` instead of exiting
+fn handle_signals() {
+    let handler = on_signal as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
     }
 }
 
 // --------------------------------------------------
-/// Returns the extension plus optional ".gz"
-fn get_extension(path: &Path) -> Option<String> {
-    let re = Regex::new(r"\.([^.]+(?:\.gz)?)$").unwrap();
-    if let Some(basename) = path.file_name() {
-        let basename = basename.to_string_lossy();
-        if let Some(cap) = re.captures(&basename) {
-            return Some(cap[1].to_string());
+/// Parses a duration in seconds, optionally with an "s", "m", "h" or "d"
+/// suffix, e.g., "90", "45m" or "2h"
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (num, unit) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c),
+        _ => (value, 's'),
+    };
+    let scale = match unit.to_ascii_lowercase() {
+        's' => 1.,
+        'm' => 60.,
+        'h' => 3600.,
+        'd' => 86400.,
+        _ => return None,
+    };
+    match num.trim().parse::<f64>() {
+        Ok(n) if n > 0. && n.is_finite() => {
+            Some(Duration::from_secs_f64(n * scale))
         }
+        _ => None,
     }
-    None
 }
 
 // --------------------------------------------------
-fn run_jobs(
-    jobs: &[String],
-    msg: &str,
-    num_concurrent_jobs: u32,
-    num_halt: u32,
-) -> MyResult<()> {
-    let num_jobs = jobs.len();
+/// Merges with the `merge` module, reporting the counts as pear would
+fn run_native(job: &PearJob) -> (JobStatus, Option<MergeStats>) {
+    let params = match merge_params(&job.options) {
+        Ok(params) => params,
+        Err(e) => return (JobStatus::Error(e.to_string()), None),
+    };
 
-    if num_jobs > 0 {
-        println!(
-            "{} (# {} job{} @ {})",
-            msg,
-            num_jobs,
-            if num_jobs == 1 { "" } else { "s" },
-            num_concurrent_jobs,
+    match merge::merge_files(
+        &job.forward,
+        &job.reverse,
+        &job.out_prefix,
+        &params,
+    ) {
+        Ok(counts) => {
+            let stats = MergeStats {
+                parameters: params.settings(),
+                ..MergeStats::from_counts(
+                    counts.assembled,
+                    counts.discarded,
+                    counts.unassembled,
+                )
+            };
+            (JobStatus::Success, Some(stats))
+        }
+        Err(e) => (JobStatus::Error(e.to_string()), None),
+    }
+}
+
+// --------------------------------------------------
+/// The native engine's settings from the pear options it supports
+fn merge_params(options: &[PearOption]) -> MyResult<merge::Params> {
+    let mut params = merge::Params::default();
+    let mut test_method = 1;
+    for opt in options {
+        match opt {
+            PearOption::MinOverlap(x) => params.min_overlap = *x as usize,
+            PearOption::MaxAssemblyLength(x) => {
+                params.max_assembly_length = *x as usize
+            }
+            PearOption::MinAssemblyLength(x) => {
+                params.min_assembly_length = *x as usize
+            }
+            PearOption::MaxUncalledBase(x) => params.max_uncalled_base = *x,
+            PearOption::PhredBase(x) => params.phred_base = *x as u8,
+            PearOption::QualityThreshold(x) => {
+                params.quality_threshold = (*x).min(u8::MAX as u32) as u8
+            }
+            PearOption::MinTrimLength(x) => {
+                params.min_trim_length = *x as usize
+            }
+            PearOption::PValue(x) => params.p_value = *x,
+            PearOption::TestMethod(x) => test_method = *x,
+            PearOption::EmpiricalFreqs => params.empirical_freqs = false,
+            PearOption::ScoreMethod(x) => params.score_method = *x,
+            PearOption::Cap(x) => params.cap = (*x).min(u8::MAX as u32) as u8,
+            PearOption::Nbase => params.nbase = true,
+            PearOption::KeepOriginal => params.keep_original = true,
+            PearOption::Stitch => params.stitch = true,
+            PearOption::StitchSpacer(x) => params.stitch_spacer = *x as usize,
+            _ => (),
+        }
+    }
+
+    if !merge::P_VALUES.contains(&params.p_value) {
+        let msg = format!(
+            "p-value {} must be one of {}",
+            params.p_value,
+            merge::P_VALUES
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        return Err(From::from(msg));
+    }
+
+    if !(1..=3).contains(&params.score_method) {
+        let msg =
+            format!("score method {} must be 1, 2 or 3", params.score_method);
+        return Err(From::from(msg));
+    }
+
+    // Only pear's first test is implemented
+    if test_method != 1 {
+        let msg = format!(
+            "test method {} is not supported by the native engine, only 1",
+            test_method
         );
+        return Err(From::from(msg));
+    }
 
-        let mut args: Vec<String> =
-            vec!["-j".to_string(), num_concurrent_jobs.to_string()];
+    Ok(params)
+}
 
-        if num_halt > 0 {
-            args.push("--halt".to_string());
-            args.push(format!("soon,fail={}", num_halt.to_string()));
+// --------------------------------------------------
+fn remove_split_files(job: &PearJob) {
+    for file in &[&job.forward, &job.reverse] {
+        if file.exists() {
+            if let Err(e) = fs::remove_file(file) {
+                eprintln!("Failed to remove \"{}\": {}", file.display(), e);
+            }
         }
+    }
+}
+
+// --------------------------------------------------
+/// Parses pear's STDOUT, e.g.:
+///
+/// ```text
+/// PEAR v0.9.11 [Nov 5, 2017]
+/// ...
+/// Forward reads file.................: R1.fastq
+/// PHRED..............................: 33
+///
+/// Allocating memory..................: 200,000,000 bytes
+/// Assembled reads ...................: 1,003 / 1,111 (90.279%)
+/// Discarded reads ...................: 0 / 1,111 (0.000%)
+/// Not assembled reads ...............: 108 / 1,111 (9.721%)
+/// ```
+///
+/// The settings block before the first blank line becomes `parameters`.
+/// Returns `None` unless all three read counts are found.
+fn parse_pear_output(text: &str) -> Option<MergeStats> {
+    let version_re = Regex::new(r"^PEAR v(\S+)").unwrap();
+    let kv_re = Regex::new(r"^\s*([^.:]+?)\s*\.+\s*:\s*(.*?)\s*$").unwrap();
+    let count_re =
+        Regex::new(r"^([\d,]+)\s*/\s*([\d,]+)\s*\(([\d.]+)%\)").unwrap();
+    let to_num = |x: &str| x.replace(',', "").parse::<u64>().ok();
 
-        let mut process = Command::new("parallel")
-            .args(args)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::null())
-            .spawn()?;
+    let mut stats = MergeStats::default();
+    let (mut in_params, mut params_done) = (false, false);
+    let (mut assembled, mut discarded, mut unassembled) = (None, None, None);
 
-        {
-            let stdin = process.stdin.as_mut().expect("Failed to open stdin");
-            stdin
-                .write_all(jobs.join("\n").as_bytes())
-                .expect("Failed to write to stdin");
+    for line in text.lines() {
+        if let Some(cap) = version_re.captures(line) {
+            stats.version = Some(cap[1].to_string());
+            continue;
+        }
+
+        if line.trim().is_empty() {
+            if in_params {
+                params_done = true;
+            }
+            continue;
+        }
+
+        if let Some(cap) = kv_re.captures(line) {
+            let (key, value) = (&cap[1], &cap[2]);
+            if let Some(count) = count_re.captures(value) {
+                let pct = count[3].parse::<f64>().unwrap_or(0.);
+                let num = to_num(&count[1]);
+                stats.total = to_num(&count[2]).unwrap_or(stats.total);
+                match key {
+                    "Assembled reads" => {
+                        assembled = num;
+                        stats.assembled_pct = pct;
+                    }
+                    "Discarded reads" => {
+                        discarded = num;
+                        stats.discarded_pct = pct;
+                    }
+                    "Not assembled reads" => {
+                        unassembled = num;
+                        stats.unassembled_pct = pct;
+                    }
+                    _ => (),
+                }
+                params_done = true;
+            } else if !params_done {
+                in_params = true;
+                stats.parameters.push((key.to_string(), value.to_string()));
+            }
         }
+    }
+
+    stats.assembled = assembled?;
+    stats.discarded = discarded?;
+    stats.unassembled = unassembled?;
+    Some(stats)
+}
+
+// --------------------------------------------------
+/// Combines the classification, plan and job results into one report
+/// per sample, sorted by sample name
+fn make_reports(
+    out_dir: &Path,
+    classified: &Classified,
+    plan: &Plan,
+    results: &[JobResult],
+) -> Vec<SampleReport> {
+    let mut reports = vec![];
+    let lookups = classified.pairs.iter().chain(classified.unpaired.iter());
+    for (sample, pair) in lookups {
+        let rejected = plan.rejected.iter().find(|(s, _)| s == sample);
+        let result = results.iter().find(|r| &r.sample == sample);
+        let (status, stats, seconds, error) = if classified
+            .unpaired
+            .contains_key(sample)
+        {
+            (SampleStatus::Unpaired, None, None, None)
+        } else if plan.skipped.contains(sample) {
+            (SampleStatus::Skipped, None, None, None)
+        } else if let Some((_, reason)) = rejected {
+            (SampleStatus::Failed, None, None, Some(reason.to_string()))
+        } else if let Some(result) = result {
+            let (status, error) = match result.status {
+                JobStatus::Success => (SampleStatus::Merged, None),
+                JobStatus::Halted => (SampleStatus::Halted, None),
+                JobStatus::Interrupted => (SampleStatus::Interrupted, None),
+                JobStatus::TimedOut(_) => {
+                    (SampleStatus::TimedOut, Some(result.status.to_string()))
+                }
+                _ => (SampleStatus::Failed, Some(result.status.to_string())),
+            };
+            (
+                status,
+                result.stats.clone(),
+                result.elapsed.map(|d| d.as_secs_f64()),
+                error,
+            )
+        } else {
+            (SampleStatus::Halted, None, None, None)
+        };
+
+        let outputs = if status == SampleStatus::Unpaired {
+            vec![]
+        } else {
+            output_files(&out_dir.join(sample).join(sample))
+        };
+
+        let lane_files = classified.lane_files.get(sample);
+        let input = |direction: &ReadDirection| match lane_files {
+            Some(files) => files.get(direction).map(|f| f.join(",")),
+            _ => pair.get(direction).cloned(),
+        };
+
+        reports.push(SampleReport {
+            sample: sample.to_string(),
+            status,
+            pattern: classified.patterns.get(sample).cloned(),
+            forward: input(&ReadDirection::Forward),
+            reverse: input(&ReadDirection::Reverse),
+            illumina: classified.illumina.get(sample).cloned(),
+            stats,
+            seconds,
+            attempts: result.map(|r| r.attempts.clone()).unwrap_or_default(),
+            outputs,
+            error,
+        });
+    }
+
+    reports.sort_by(|a, b| a.sample.cmp(&b.sample));
+    reports
+}
+
+// --------------------------------------------------
+/// The pear output files existing for a prefix
+fn output_files(out_prefix: &Path) -> Vec<String> {
+    let pattern = format!(
+        "{}.*.fastq*",
+        glob::Pattern::escape(&out_prefix.display().to_string())
+    );
+
+    match glob(&pattern) {
+        Ok(paths) => paths
+            .filter_map(Result::ok)
+            .map(|p| p.display().to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// --------------------------------------------------
+/// Writes "summary.tsv" and "summary.json" into the output directory.
+/// TSV fields holding a tab, newline or quote are quoted.
+fn write_summary(out_dir: &Path, reports: &[SampleReport]) -> MyResult<()> {
+    if !out_dir.is_dir() {
+        DirBuilder::new().recursive(true).create(out_dir)?;
+    }
+
+    let mut tsv = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(out_dir.join("summary.tsv"))?;
+    tsv.write_record([
+        "sample",
+        "status",
+        "pattern",
+        "forward",
+        "reverse",
+        "assembled",
+        "discarded",
+        "unassembled",
+        "total",
+        "assembled_pct",
+        "seconds",
+        "attempts",
+        "outputs",
+        "error",
+    ])?;
+
+    for report in reports {
+        let stat = |f: fn(&MergeStats) -> String| {
+            report.stats.as_ref().map(f).unwrap_or_default()
+        };
 
-        let result = process.wait()?;
-        if !result.success() {
-            return Err(From::from("Failed to run jobs in parallel"));
-        }
+        tsv.write_record([
+            report.sample.to_string(),
+            report.status.to_string(),
+            report.pattern.clone().unwrap_or_default(),
+            report.forward.clone().unwrap_or_default(),
+            report.reverse.clone().unwrap_or_default(),
+            stat(|s| s.assembled.to_string()),
+            stat(|s| s.discarded.to_string()),
+            stat(|s| s.unassembled.to_string()),
+            stat(|s| s.total.to_string()),
+            stat(|s| format!("{:.3}", s.assembled_pct)),
+            report
+                .seconds
+                .map(|x| format!("{:.1}", x))
+                .unwrap_or_default(),
+            report.attempts.len().to_string(),
+            report.outputs.join(","),
+            report.error.clone().unwrap_or_default(),
+        ])?;
     }
+    tsv.flush()?;
+
+    let json = File::create(out_dir.join("summary.json"))?;
+    serde_json::to_writer_pretty(json, reports)?;
 
     Ok(())
 }
 
+// --------------------------------------------------
+/// Quotes a word for a POSIX shell if it contains anything unsafe
+fn shell_quote(word: &str) -> String {
+    let is_safe =
+        |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
 // --------------------------------------------------
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// A pear job for "<dir>/<sample>_R1.fastq" and "_R2.fastq" writing
+    /// to "<dir>/<sample>/<sample>"
+    fn job(sample: &str, dir: &Path) -> PearJob {
+        PearJob {
+            sample: sample.to_string(),
+            engine: Engine::External,
+            merger: &merger::Pear,
+            program: "pear".to_string(),
+            forward: dir.join(format!("{}_R1.fastq", sample)),
+            reverse: dir.join(format!("{}_R2.fastq", sample)),
+            out_prefix: dir.join(sample).join(sample),
+            options: vec![],
+            interleaved: None,
+            timeout: None,
+            staging: None,
+            log_prefix: None,
+        }
+    }
+
     #[test]
     fn test_get_extension() {
         assert_eq!(
@@ -652,19 +3808,32 @@ mod tests {
 
     #[test]
     fn test_classify() {
-        assert!(classify(&["ERR1711926.fastq.gz".to_string()]).is_err());
-
-        let res = classify(&[
-            "/foo/bar/ERR1711926_1.fastq.gz".to_string(),
-            "/foo/bar/ERR1711926_2.fastq.gz".to_string(),
-            "/foo/bar/ERR1711927-R1.fastq.gz".to_string(),
-            "/foo/bar/ERR1711927_R2.fastq.gz".to_string(),
-            "/foo/bar/ERR1711928.fastq.gz".to_string(),
-            "/foo/bar/ERR1711929_1.fastq.gz".to_string(),
-        ]);
+        assert!(
+            classify(&["ERR1711926.fastq.gz".to_string()], &[], false).is_err()
+        );
+
+        let res = classify(
+            &[
+                "/foo/bar/ERR1711926_1.fastq.gz".to_string(),
+                "/foo/bar/ERR1711926_2.fastq.gz".to_string(),
+                "/foo/bar/ERR1711927-R1.fastq.gz".to_string(),
+                "/foo/bar/ERR1711927_R2.fastq.gz".to_string(),
+                "/foo/bar/ERR1711928.fastq.gz".to_string(),
+                "/foo/bar/ERR1711929_1.fastq.gz".to_string(),
+            ],
+            &[],
+            false,
+        );
         assert!(res.is_ok());
 
         if let Ok(res) = res {
+            assert_eq!(res.unpaired.len(), 1);
+            assert!(res.unpaired.contains_key("ERR1711929"));
+            assert_eq!(res.unmatched, vec!["/foo/bar/ERR1711928.fastq.gz"]);
+            assert!(check_classified(&res, false).is_ok());
+            assert!(check_classified(&res, true).is_err());
+
+            let res = res.pairs;
             assert!(res.len() == 2);
             assert!(res.contains_key("ERR1711926"));
             assert!(res.contains_key("ERR1711927"));
@@ -696,4 +3865,1122 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_classify_patterns() {
+        let files = [
+            "Sample_S3_L001_R1_001.fastq.gz".to_string(),
+            "Sample_S3_L001_R2_001.fastq.gz".to_string(),
+            "foo.R1.fq".to_string(),
+            "foo.R2.fq".to_string(),
+            "bar_1_trimmed.fq.gz".to_string(),
+            "bar_2_trimmed.fq.gz".to_string(),
+            "baz_read1.fastq".to_string(),
+            "baz_read2.fastq".to_string(),
+            "qux.read1.fq.bz2".to_string(),
+            "qux.read2.fq.bz2".to_string(),
+        ];
+
+        let res = classify(&files, &[], false);
+        assert!(res.is_ok());
+        if let Ok(res) = res {
+            assert_eq!(res.pairs.len(), 5);
+            assert_eq!(
+                res.patterns.get("Sample"),
+                Some(&"illumina".to_string())
+            );
+            assert_eq!(res.patterns.get("foo"), Some(&"dot_r".to_string()));
+            assert_eq!(res.patterns.get("bar"), Some(&"trimmed".to_string()));
+            assert_eq!(res.patterns.get("baz"), Some(&"read".to_string()));
+            assert_eq!(res.patterns.get("qux"), Some(&"read".to_string()));
+            assert!(res.unmatched.is_empty());
+        }
+
+        let custom = r"^(?P<sample>.+)_(?P<read>[12])_trimmed".to_string();
+        let res =
+            classify(&files, &[custom.to_string(), "dot_r".to_string()], false);
+        assert!(res.is_ok());
+        if let Ok(res) = res {
+            assert_eq!(res.pairs.len(), 2);
+            assert_eq!(res.patterns.get("bar"), Some(&custom));
+            assert!(res.pairs.contains_key("foo"));
+            assert!(!res.pairs.contains_key("Sample"));
+        }
+
+        // Sidecar files next to the reads are not paired
+        let res = classify(
+            &[
+                "foo_1.fastq".to_string(),
+                "foo_1.fastq.md5".to_string(),
+                "foo_2.fastq".to_string(),
+                "foo_2.fastq.md5".to_string(),
+            ],
+            &[],
+            false,
+        )
+        .unwrap();
+        assert_eq!(res.patterns.get("foo"), Some(&"sra".to_string()));
+        assert_eq!(
+            res.pairs["foo"].get(&ReadDirection::Forward),
+            Some(&"foo_1.fastq".to_string())
+        );
+        assert_eq!(res.unmatched, vec!["foo_1.fastq.md5", "foo_2.fastq.md5"]);
+
+        // Two files claiming the same read of a sample
+        let res = classify(
+            &[
+                "foo_1.fastq".to_string(),
+                "foo_1.fq.gz".to_string(),
+                "foo_2.fastq".to_string(),
+            ],
+            &[],
+            false,
+        );
+        assert!(res.is_err());
+
+        let bad = ["(?P<sample>.+)".to_string()];
+        assert!(classify(&files, &bad, false).is_err());
+        assert!(classify(&files, &["(".to_string()], false).is_err());
+    }
+
+    #[test]
+    fn test_classify_lanes() {
+        let files = [
+            "/run/Foo_S3_L002_R1_001.fastq.gz".to_string(),
+            "/run/Foo_S3_L001_R1_001.fastq.gz".to_string(),
+            "/run/Foo_S3_L001_R2_001.fastq.gz".to_string(),
+            "/run/Foo_S3_L002_R2_001.fastq.gz".to_string(),
+            "/run/Bar_S4_L001_R1_001.fastq.gz".to_string(),
+            "/run/Bar_S4_L001_R2_001.fastq.gz".to_string(),
+        ];
+
+        let res = classify(&files, &[], false);
+        assert!(res.is_ok());
+        if let Ok(res) = res {
+            assert_eq!(res.pairs.len(), 3);
+            assert!(res.pairs.contains_key("Foo_L001"));
+            assert!(res.pairs.contains_key("Foo_L002"));
+            assert!(res.lane_files.is_empty());
+            assert_eq!(
+                res.illumina.get("Foo_L002"),
+                Some(&IlluminaMeta {
+                    index: Some("3".to_string()),
+                    lanes: vec!["002".to_string()],
+                })
+            );
+
+            // A single lane keeps the bare sample name
+            assert!(res.pairs.contains_key("Bar"));
+            assert_eq!(res.illumina["Bar"].lanes, vec!["001".to_string()]);
+        }
+
+        let res = classify(&files, &[], true);
+        assert!(res.is_ok());
+        if let Ok(res) = res {
+            assert_eq!(res.pairs.len(), 2);
+            assert_eq!(
+                res.illumina.get("Foo"),
+                Some(&IlluminaMeta {
+                    index: Some("3".to_string()),
+                    lanes: vec!["001".to_string(), "002".to_string()],
+                })
+            );
+            assert!(!res.lane_files.contains_key("Bar"));
+            assert_eq!(
+                res.lane_files["Foo"][&ReadDirection::Reverse],
+                vec![
+                    "/run/Foo_S3_L001_R2_001.fastq.gz",
+                    "/run/Foo_S3_L002_R2_001.fastq.gz"
+                ]
+            );
+
+            let mut pairs = res.pairs.clone();
+            let concats = plan_concats(&res, Path::new("/out"), &mut pairs);
+            assert_eq!(concats.len(), 2);
+            assert_eq!(
+                concats[0].target,
+                PathBuf::from("/out/.run_pear/lanes/Foo/Foo_R1.fastq.gz")
+            );
+            assert_eq!(
+                pairs["Foo"][&ReadDirection::Forward],
+                "/out/.run_pear/lanes/Foo/Foo_R1.fastq.gz"
+            );
+        }
+
+        // A lane missing its mate
+        let missing: Vec<String> = files
+            .iter()
+            .filter(|f| !f.contains("L002_R2"))
+            .cloned()
+            .collect();
+        let res = classify(&missing, &[], true);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_read_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in &["a_1.fq", "a_2.fq", "b_1.fq", "b_2.fq"] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+
+        let manifest = dir.path().join("samples.csv");
+        fs::write(
+            &manifest,
+            "sample,forward,reverse,p_value,stitch\n\
+             a,a_1.fq,a_2.fq,0.05,\n\
+             b,b_1.fq,b_2.fq,,yes\n",
+        )
+        .unwrap();
+
+        let res = read_manifest(&manifest);
+        assert!(res.is_ok());
+        if let Ok((classified, params)) = res {
+            assert_eq!(classified.pairs.len(), 2);
+            assert_eq!(
+                classified.pairs["a"][&ReadDirection::Forward],
+                dir.path().join("a_1.fq").display().to_string()
+            );
+
+            let mut options = vec![PearOption::PValue(0.01), PearOption::Nbase];
+            for (name, value) in &params["a"] {
+                assert!(set_pear_option(&mut options, name, value).is_ok());
+            }
+            assert_eq!(
+                options,
+                vec![PearOption::Nbase, PearOption::PValue(0.05)]
+            );
+        }
+
+        let bad = dir.path().join("bad.tsv");
+        fs::write(
+            &bad,
+            "sample\tforward\treverse\n\
+             a\ta_1.fq\ta_2.fq\n\
+             a\tb_1.fq\tb_2.fq\n\
+             c\tc_1.fq\tc_2.fq\n\
+             ../x\ta_1.fq\ta_2.fq\n\
+             ..\ta_1.fq\ta_2.fq\n\
+             .run_pear\ta_1.fq\ta_2.fq\n",
+        )
+        .unwrap();
+
+        let res = read_manifest(&bad);
+        assert!(res.is_err());
+        if let Err(e) = res {
+            let msg = e.to_string();
+            assert!(msg.contains("duplicate sample \"a\""));
+            assert!(msg.contains("c_1.fq\" does not exist"));
+            assert!(msg.contains("line 5: sample \"../x\" cannot contain"));
+            assert!(msg.contains("line 6: sample \"..\" cannot"));
+            assert!(msg.contains("line 7: sample \".run_pear\" cannot"));
+        }
+
+        let typo = dir.path().join("typo.tsv");
+        fs::write(
+            &typo,
+            "sample\tforward\treverse\tp_vlaue\na\ta_1.fq\ta_2.fq\t1\n",
+        )
+        .unwrap();
+        assert!(read_manifest(&typo).is_err());
+    }
+
+    #[test]
+    fn test_find_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for name in &[
+            "a_1.fq",
+            "a_1.fq.md5",
+            ".hidden_1.fq",
+            "run1/b_1.fq",
+            "run1/deep/c_1.fq",
+            "run1/report.html",
+            ".git/d_1.fq",
+            "out/a/a.assembled.fastq",
+        ] {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+
+        let query = vec![root.display().to_string()];
+        let found = |opts: &FindOptions| -> Vec<String> {
+            find_files(&query, opts)
+                .unwrap_or_default()
+                .iter()
+                .map(|f| {
+                    Path::new(f)
+                        .strip_prefix(root)
+                        .unwrap()
+                        .display()
+                        .to_string()
+                })
+                .collect()
+        };
+
+        let opts = FindOptions::default();
+        assert_eq!(found(&opts), vec!["a_1.fq", "a_1.fq.md5"]);
+
+        let opts = FindOptions {
+            recursive: true,
+            include: vec![glob::Pattern::new("*.fq").unwrap()],
+            skip_dir: fs::canonicalize(root.join("out")).ok(),
+            ..Default::default()
+        };
+        assert_eq!(
+            found(&opts),
+            vec!["a_1.fq", "run1/b_1.fq", "run1/deep/c_1.fq"]
+        );
+
+        let opts = FindOptions {
+            recursive: true,
+            max_depth: Some(2),
+            hidden: true,
+            exclude: vec![
+                glob::Pattern::new("*.md5").unwrap(),
+                glob::Pattern::new("*.html").unwrap(),
+                glob::Pattern::new("out").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            found(&opts),
+            vec![".git/d_1.fq", ".hidden_1.fq", "a_1.fq", "run1/b_1.fq"]
+        );
+    }
+
+    #[test]
+    fn test_deinterleave() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("s.fq.gz");
+        let text = "@r1/1\nACGT\n+\nIIII\n@r1/2\nTTGA\n+\nHHHH\n\
+                    @r2/1\nGG\n+\nII\n@r2/2\nCC\n+\nII\n";
+        let mut gz = flate2::write::GzEncoder::new(
+            File::create(&source).unwrap(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(text.as_bytes()).unwrap();
+        gz.finish().unwrap();
+
+        let (fwd, rev) = (dir.path().join("r1.fq"), dir.path().join("r2.fq"));
+        assert!(deinterleave(&source, &fwd, &rev).is_ok());
+        assert_eq!(
+            fs::read_to_string(&fwd).unwrap(),
+            "@r1/1\nACGT\n+\nIIII\n@r2/1\nGG\n+\nII\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&rev).unwrap(),
+            "@r1/2\nTTGA\n+\nHHHH\n@r2/2\nCC\n+\nII\n"
+        );
+
+        let odd = dir.path().join("odd.fq");
+        fs::write(&odd, "@r1/1\nACGT\n+\nIIII\n").unwrap();
+        assert!(deinterleave(&odd, &fwd, &rev).is_err());
+
+        let res = classify_interleaved(&[
+            "/a/s1.fastq.gz".to_string(),
+            "/a/s2.fq".to_string(),
+        ]);
+        assert!(res.is_ok());
+        if let Ok(res) = res {
+            assert!(res.pairs.contains_key("s1"));
+            assert!(res.pairs.contains_key("s2"));
+        }
+
+        let res = classify_interleaved(&[
+            "/a/s1.fastq.gz".to_string(),
+            "/b/s1.fastq.gz".to_string(),
+        ]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_verify_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, text: &str| -> PathBuf {
+            let path = dir.path().join(name);
+            fs::write(&path, text).unwrap();
+            path
+        };
+
+        let r1 = write(
+            "r1.fq",
+            "@A:1:FC:1:1101:1 1:N:0:ATCACG\nACGT\n+\nIIII\n\
+             @A:1:FC:1:1101:2 1:N:0:ATCACG\nACGT\n+\nIIII\n",
+        );
+        let r2 = write(
+            "r2.fq",
+            "@A:1:FC:1:1101:1 2:N:0:ATCACG\nTTTT\n+\nIIII\n\
+             @A:1:FC:1:1101:2 2:N:0:ATCACG\nTTTT\n+\nIIII\n\n",
+        );
+        assert!(verify_pair(&r1, &r2).is_ok());
+
+        let old1 = write("old1.fq", "@read7/1\nAC\n+\nII\n");
+        let old2 = write("old2.fq", "@read7/2\nGT\n+\nII\n");
+        assert!(verify_pair(&old1, &old2).is_ok());
+
+        let other = write("other.fq", "@read8/2\nGT\n+\nII\n");
+        let res = verify_pair(&old1, &other);
+        assert!(res.is_err());
+        if let Err(e) = res {
+            assert!(e.to_string().contains("\"read7\""));
+        }
+
+        let res = verify_pair(&r1, &old1);
+        assert!(res.is_err());
+
+        let short = write("short.fq", "@A:1:FC:1:1101:1\nACGT\n+\nIIII\n");
+        let res = verify_pair(&r1, &short);
+        assert!(res.is_err());
+        if let Err(e) = res {
+            assert!(e.to_string().contains("more than the 1 records"));
+        }
+
+        let truncated = write("trunc.fq", "@A:1:FC:1:1101:1\nACGT\n");
+        assert!(verify_pair(&r1, &truncated).is_err());
+    }
+
+    #[test]
+    fn test_detect_phred() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, text: &str| -> PathBuf {
+            let path = dir.path().join(name);
+            fs::write(&path, text).unwrap();
+            path
+        };
+
+        let p33 = write("p33.fq", "@r1\nACGT\n+\nII#5\n@r2\nAC\n+\nFF\n");
+        assert_eq!(detect_phred(&p33, 100).unwrap(), Some(33));
+
+        let p64 = write("p64.fq", "@r1\nACGT\n+\nhhB_\n");
+        assert_eq!(detect_phred(&p64, 100).unwrap(), Some(64));
+
+        let both = write("both.fq", "@r1\nACGT\n+\nIIII\n");
+        assert_eq!(detect_phred(&both, 100).unwrap(), None);
+
+        let empty = write("empty.fq", "");
+        assert_eq!(detect_phred(&empty, 100).unwrap(), None);
+
+        // Only the first record is read
+        let late = write("late.fq", "@r1\nAC\n+\nhh\n@r2\nAC\n+\n##\n");
+        assert_eq!(detect_phred(&late, 1).unwrap(), Some(64));
+
+        let phred_job =
+            |sample: &str, fwd: &Path, rev: &Path, options| PearJob {
+                forward: fwd.to_path_buf(),
+                reverse: rev.to_path_buf(),
+                options,
+                ..job(sample, dir.path())
+            };
+
+        let classified = classify_interleaved(&["x.fq".to_string()]).unwrap();
+        let mut plan = Plan {
+            jobs: vec![
+                phred_job("a", &p64, &both, vec![]),
+                phred_job("b", &p33, &p64, vec![]),
+                phred_job("c", &p33, &p33, vec![PearOption::PhredBase(64)]),
+            ],
+            skipped: vec![],
+            concats: vec![],
+            rejected: vec![],
+            phred: HashMap::new(),
+        };
+        detect_plan_phred(&mut plan, &classified, 100, true, 2);
+        assert_eq!(plan.jobs.len(), 1);
+        assert_eq!(plan.jobs[0].options, vec![PearOption::PhredBase(64)]);
+        assert_eq!(plan.rejected.len(), 2);
+        assert_eq!(plan.rejected[0].0, "b");
+        assert!(plan.rejected[1].1.contains("phred_base is 64"));
+
+        // Resuming runs before detection, so a completed job is skipped
+        // if its recorded command only adds the detected base
+        let mut state = State::load(dir.path()).unwrap();
+        assert_eq!(plan.phred.get("a"), Some(&64));
+        state
+            .start("a", &plan.jobs[0].to_string(), &[], Some(64))
+            .unwrap();
+        state.finish("a", RunState::Completed, &[]).unwrap();
+        let plan_for = |job: PearJob| Plan {
+            jobs: vec![job],
+            skipped: vec![],
+            concats: vec![],
+            rejected: vec![],
+            phred: HashMap::new(),
+        };
+        let mut plan = plan_for(phred_job("a", &p64, &both, vec![]));
+        resume_plan(&mut plan, &state, &classified, true);
+        assert_eq!(plan.skipped, ["a"]);
+        let mut plan = plan_for(phred_job("a", &p64, &both, vec![]));
+        resume_plan(&mut plan, &state, &classified, false);
+        assert!(plan.skipped.is_empty());
+
+        // The base recorded last time is the one compared, and without
+        // one only the command as planned matches
+        let mut with_33 = phred_job("a", &p64, &both, vec![]);
+        add_phred_base(&mut with_33, 33);
+        state
+            .start("a", &with_33.to_string(), &[], Some(33))
+            .unwrap();
+        state.finish("a", RunState::Completed, &[]).unwrap();
+        let mut plan = plan_for(phred_job("a", &p64, &both, vec![]));
+        resume_plan(&mut plan, &state, &classified, true);
+        assert_eq!(plan.skipped, ["a"]);
+        state.start("a", &with_33.to_string(), &[], None).unwrap();
+        state.finish("a", RunState::Completed, &[]).unwrap();
+        let mut plan = plan_for(phred_job("a", &p64, &both, vec![]));
+        resume_plan(&mut plan, &state, &classified, true);
+        assert!(plan.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_shell_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let interleaved = |source: &str| PearJob {
+            interleaved: Some(PathBuf::from(source)),
+            ..job("a", Path::new(""))
+        };
+        let plan = Plan {
+            jobs: vec![
+                interleaved("a.fq.bz2"),
+                interleaved("a.fq.zst"),
+                interleaved("a.fq.gz"),
+            ],
+            skipped: vec![],
+            concats: vec![],
+            rejected: vec![],
+            phred: HashMap::new(),
+        };
+        let out = dir.path().join("plan.sh");
+        assert!(
+            write_plan(&plan, PlanFormat::Shell, &Some(out.clone())).is_ok()
+        );
+        let script = fs::read_to_string(&out).unwrap();
+        let lines: Vec<&str> =
+            script.lines().filter(|l| l.starts_with("mkdir")).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("&& bzip2 -dc a.fq.bz2 | paste"));
+        assert!(lines[1].contains("&& zstd -dc a.fq.zst | paste"));
+        assert!(lines[2].contains("&& gzip -cdf a.fq.gz | paste"));
+    }
+
+    #[test]
+    fn test_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = ["a_1.fq", "a_2.fq", "b_1.fq", "b_2.fq"]
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        let classified = classify(&files, &[], false).unwrap();
+        let plan = Plan {
+            jobs: vec![],
+            skipped: vec![],
+            concats: vec![],
+            rejected: vec![],
+            phred: HashMap::new(),
+        };
+        let results = vec![
+            JobResult {
+                sample: "a".to_string(),
+                status: JobStatus::Success,
+                stats: Some(MergeStats::from_counts(8, 1, 1)),
+                elapsed: Some(Duration::from_millis(1500)),
+                attempts: vec![],
+            },
+            JobResult {
+                sample: "b".to_string(),
+                status: JobStatus::Error("bad\tinput\n\"b_1.fq\"".to_string()),
+                stats: None,
+                elapsed: None,
+                attempts: vec![],
+            },
+        ];
+        let reports = make_reports(dir.path(), &classified, &plan, &results);
+        assert!(write_summary(dir.path(), &reports).is_ok());
+
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_path(dir.path().join("summary.tsv"))
+            .unwrap();
+        let headers = reader.headers().unwrap().clone();
+        assert_eq!(headers.len(), 14);
+        let rows: Vec<csv::StringRecord> =
+            reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        let field = |row: &csv::StringRecord, name: &str| {
+            row[headers.iter().position(|h| h == name).unwrap()].to_string()
+        };
+        assert_eq!(field(&rows[0], "sample"), "a");
+        assert_eq!(field(&rows[0], "status"), "merged");
+        assert_eq!(field(&rows[0], "assembled"), "8");
+        assert_eq!(field(&rows[0], "assembled_pct"), "80.000");
+        assert_eq!(field(&rows[0], "seconds"), "1.5");
+        assert_eq!(field(&rows[1], "sample"), "b");
+        assert_eq!(field(&rows[1], "status"), "failed");
+        assert_eq!(field(&rows[1], "forward"), "b_1.fq");
+        assert_eq!(field(&rows[1], "assembled"), "");
+        assert_eq!(
+            field(&rows[1], "error"),
+            JobStatus::Error("bad\tinput\n\"b_1.fq\"".to_string()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let in_dir = dir.path().join("in");
+        let out_dir = dir.path().join("out");
+        fs::create_dir(&in_dir).unwrap();
+        for lane in &["L001", "L002"] {
+            for read in &["R1", "R2"] {
+                fs::write(
+                    in_dir.join(format!("s1_S1_{}_{}_001.fastq", lane, read)),
+                    "@r1\nACGT\n+\nIIII\n",
+                )
+                .unwrap();
+            }
+        }
+        let plan = dir.path().join("plan.sh");
+
+        let config = get_args_from([
+            "run_pear",
+            "-Q",
+            &in_dir.display().to_string(),
+            "-o",
+            &out_dir.display().to_string(),
+            "--merge_lanes",
+            "--verify_pairs",
+            "--resume",
+            "--phred_records",
+            "10",
+            "--dry_run",
+            "--plan_format",
+            "sh",
+            "--plan_out",
+            &plan.display().to_string(),
+        ])
+        .unwrap();
+        assert!(run(config).is_ok());
+
+        // The plan is written and nothing else
+        let script = fs::read_to_string(&plan).unwrap();
+        assert!(script.contains("cat "));
+        assert!(!out_dir.exists());
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["in", "plan.sh"]);
+        assert_eq!(fs::read_dir(&in_dir).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_merge_lanes() {
+        let dir = tempfile::tempdir().unwrap();
+        let in_dir = dir.path().join("in");
+        let out_dir = dir.path().join("out");
+        fs::create_dir(&in_dir).unwrap();
+        for lane in &["L001", "L002"] {
+            for read in &["R1", "R2"] {
+                fs::write(
+                    in_dir.join(format!("s1_S1_{}_{}_001.fastq", lane, read)),
+                    "@r1\nACGT\n+\nIIII\n",
+                )
+                .unwrap();
+            }
+        }
+
+        let config = get_args_from([
+            "run_pear",
+            "-Q",
+            &in_dir.display().to_string(),
+            "-o",
+            &out_dir.display().to_string(),
+            "--merge_lanes",
+            "--engine",
+            "native",
+        ])
+        .unwrap();
+        assert!(run(config).is_ok());
+
+        // The concatenated lanes are gone, the merged outputs are not
+        let mut names: Vec<String> = fs::read_dir(out_dir.join("s1"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert!(names.contains(&"s1.assembled.fastq".to_string()));
+        assert!(names
+            .iter()
+            .all(|n| !n.contains("_R1") && !n.contains("_R2")));
+        let lanes = lanes_dir(&out_dir);
+        assert!(!lanes.exists() || fs::read_dir(&lanes).unwrap().count() == 0);
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("foo_1.fq.gz"), "foo_1.fq.gz");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my reads.fq"), "'my reads.fq'");
+        assert_eq!(shell_quote("a;rm -rf"), "'a;rm -rf'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_pear_job() {
+        let job = PearJob {
+            forward: PathBuf::from("/data/my run/s1_1.fq"),
+            reverse: PathBuf::from("/data/my run/s1_2.fq"),
+            out_prefix: PathBuf::from("/out/s1/s1"),
+            options: vec![PearOption::PValue(0.01), PearOption::Stitch],
+            ..job("s1", Path::new("/data/my run"))
+        };
+
+        assert_eq!(
+            job.args(),
+            vec![
+                "-f",
+                "/data/my run/s1_1.fq",
+                "-r",
+                "/data/my run/s1_2.fq",
+                "-o",
+                "/out/s1/s1",
+                "-p",
+                "0.01",
+                "-i"
+            ]
+        );
+
+        assert_eq!(
+            job.to_string(),
+            "pear -f '/data/my run/s1_1.fq' -r '/data/my run/s1_2.fq' \
+             -o /out/s1/s1 -p 0.01 -i"
+        );
+
+        // The native engine only has pear's first statistical test
+        assert!(merge_params(&[PearOption::TestMethod(1)]).is_ok());
+        assert!(merge_params(&[PearOption::TestMethod(2)]).is_err());
+    }
+
+    #[test]
+    fn test_parse_pear_output() {
+        assert_eq!(parse_pear_output(""), None);
+
+        let text = "\
+ ____  _____    _    ____
+|  _ \\| ____|  / \\  |  _ \\
+PEAR v0.9.11 [Nov 5, 2017]
+
+Citation - PEAR: a fast and accurate Illumina Paired-End reAd mergeR
+
+Forward reads file.................: R1.fastq
+Reverse reads file.................: R2.fastq
+PHRED..............................: 33
+Using empirical frequencies........: YES
+p-value............................: 0.010000
+Quality score threshold (trimming).: 0
+
+Allocating memory..................: 200,000,000 bytes
+Computing empirical frequencies....: DONE
+  A: 0.256979
+Assemblying reads: 100%
+
+Assembled reads ...................: 1,003 / 1,111 (90.279%)
+Discarded reads ...................: 0 / 1,111 (0.000%)
+Not assembled reads ...............: 108 / 1,111 (9.721%)
+Assembled reads file...............: out.assembled.fastq
+";
+
+        let stats = parse_pear_output(text);
+        assert!(stats.is_some());
+
+        if let Some(stats) = stats {
+            assert_eq!(stats.version, Some("0.9.11".to_string()));
+            assert_eq!(stats.assembled, 1003);
+            assert_eq!(stats.discarded, 0);
+            assert_eq!(stats.unassembled, 108);
+            assert_eq!(stats.total, 1111);
+            assert!((stats.assembled_pct - 90.279).abs() < 1e-9);
+            assert!((stats.unassembled_pct - 9.721).abs() < 1e-9);
+            assert_eq!(stats.parameters.len(), 6);
+            assert_eq!(
+                stats.parameters[2],
+                ("PHRED".to_string(), "33".to_string())
+            );
+            assert_eq!(
+                stats.parameters[5],
+                (
+                    "Quality score threshold (trimming)".to_string(),
+                    "0".to_string()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_jobs() {
+        let run = |sample: &str, program: &str| PearJob {
+            program: program.to_string(),
+            ..job(sample, Path::new(""))
+        };
+        let retry = RetryPolicy::default();
+
+        let jobs = vec![run("a", "true"), run("b", "false"), run("c", "true")];
+        let res = run_jobs(&jobs, "Testing", 2, 0, &retry, |_, _| ()).unwrap();
+        let statuses: Vec<JobStatus> =
+            res.into_iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                JobStatus::Success,
+                JobStatus::Failed(Some(1)),
+                JobStatus::Success
+            ]
+        );
+
+        // One worker and halt on first failure: the rest are never run
+        let jobs = vec![run("a", "false"), run("b", "true"), run("c", "true")];
+        let res = run_jobs(&jobs, "Testing", 1, 1, &retry, |_, _| ()).unwrap();
+        assert_eq!(res[0].status, JobStatus::Failed(Some(1)));
+        assert_eq!(res[1].status, JobStatus::Halted);
+        assert_eq!(res[2].status, JobStatus::Halted);
+
+        let jobs = vec![run("a", "/no/such/pear")];
+        let res = run_jobs(&jobs, "Testing", 1, 1, &retry, |_, _| ()).unwrap();
+        match &res[0].status {
+            JobStatus::Error(_) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn test_staging() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_prefix = dir.path().join("s1").join("s1");
+        let staging = dir.path().join(".run_pear").join("staging").join("s1");
+        fs::create_dir_all(out_prefix.parent().unwrap()).unwrap();
+        fs::create_dir_all(&staging).unwrap();
+
+        // New outputs replace the old, other files are left alone
+        let old = dir.path().join("s1").join("s1.assembled.fastq.gz");
+        let input = dir.path().join("s1").join("s1_R1.fastq");
+        fs::write(&old, "old").unwrap();
+        fs::write(&input, "input").unwrap();
+        fs::write(staging.join("s1.assembled.fastq"), "new").unwrap();
+        assert!(publish(&staging, &out_prefix).is_ok());
+        assert!(!old.exists());
+        assert!(input.exists());
+        assert_eq!(
+            fs::read_to_string(
+                dir.path().join("s1").join("s1.assembled.fastq")
+            )
+            .unwrap(),
+            "new"
+        );
+
+        // A failed publish leaves the old outputs in place
+        let blocker = dir.path().join("s1").join("s1.unassembled.forward");
+        fs::create_dir_all(blocker.join("x")).unwrap();
+        fs::write(staging.join("s1.assembled.fastq"), "newer").unwrap();
+        fs::create_dir_all(staging.join("s1.unassembled.forward").join("y"))
+            .unwrap();
+        assert!(publish(&staging, &out_prefix).is_err());
+        assert_eq!(
+            fs::read_to_string(
+                dir.path().join("s1").join("s1.assembled.fastq")
+            )
+            .unwrap(),
+            "new"
+        );
+        assert!(!staging
+            .join(OLD_OUTPUTS)
+            .join("s1.assembled.fastq")
+            .exists());
+        fs::remove_dir_all(&blocker).unwrap();
+        fs::remove_dir_all(&staging).unwrap();
+        fs::create_dir_all(&staging).unwrap();
+
+        // Old outputs moved aside by a publish cut short are restored
+        fs::create_dir_all(staging.join(OLD_OUTPUTS)).unwrap();
+        fs::rename(
+            dir.path().join("s1").join("s1.assembled.fastq"),
+            staging.join(OLD_OUTPUTS).join("s1.assembled.fastq"),
+        )
+        .unwrap();
+        assert!(clean_staging(dir.path(), false).is_ok());
+        assert!(dir.path().join("s1").join("s1.assembled.fastq").exists());
+        fs::create_dir_all(&staging).unwrap();
+
+        // A job reporting success without its outputs is not published
+        let job = PearJob {
+            program: "true".to_string(),
+            staging: Some(staging.with_file_name("s2")),
+            ..job("s2", dir.path())
+        };
+        match run_job(&job, 0).0 {
+            JobStatus::Invalid(_) => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(!staging.with_file_name("s2").exists());
+        assert!(!dir.path().join("s2").exists());
+
+        // Leftovers are only reported on a dry run
+        assert!(clean_staging(dir.path(), true).is_ok());
+        assert!(staging.exists());
+        assert!(clean_staging(dir.path(), false).is_ok());
+        assert!(!staging.exists());
+    }
+
+    #[test]
+    fn test_retry() {
+        assert_eq!(scale_memory("200M", 2), "400M");
+        assert_eq!(scale_memory("4G", 4), "16G");
+        assert_eq!(scale_memory("1000", 2), "2000");
+        assert_eq!(scale_memory("1.5G", 2), "1.5G");
+
+        let job = PearJob {
+            program: "false".to_string(),
+            options: vec![
+                PearOption::Memory("1G".to_string()),
+                PearOption::Threads(8),
+            ],
+            ..job("a", Path::new(""))
+        };
+
+        let retry = RetryPolicy {
+            retries: 3,
+            delay: Duration::from_secs(5),
+            more_memory: true,
+            fewer_threads: true,
+        };
+        assert_eq!(retry.job(&job, 0).options, job.options);
+        assert_eq!(
+            retry.job(&job, 2).options,
+            vec![PearOption::Memory("4G".to_string()), PearOption::Threads(2)]
+        );
+        assert_eq!(retry.job(&job, 5).options[1], PearOption::Threads(1));
+        assert_eq!(retry.delay(1), Duration::from_secs(5));
+        assert_eq!(retry.delay(3), Duration::from_secs(20));
+        assert_eq!(retry.delay(40), MAX_BACKOFF);
+        assert_eq!(retry.delay(u32::MAX), MAX_BACKOFF);
+        let long = RetryPolicy {
+            delay: Duration::from_secs(u64::MAX),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(long.delay(2), Duration::from_secs(u64::MAX));
+
+        // Only the memory grows unless asked to cut threads
+        let retry = RetryPolicy {
+            fewer_threads: false,
+            ..retry
+        };
+        assert_eq!(retry.job(&job, 1).options[1], PearOption::Threads(8));
+
+        // Every attempt is recorded, and only the last counts to halt
+        let retry = RetryPolicy {
+            retries: 2,
+            delay: Duration::from_secs(0),
+            ..retry
+        };
+        let jobs = vec![
+            job.clone(),
+            PearJob {
+                options: vec![],
+                ..job
+            },
+        ];
+        let res = run_jobs(&jobs, "Testing", 1, 2, &retry, |_, _| ()).unwrap();
+        assert_eq!(res[0].status, JobStatus::Failed(Some(1)));
+        let attempts = &res[0].attempts;
+        assert_eq!(attempts.len(), 3);
+        assert_eq!(attempts[2].memory, Some("4G".to_string()));
+        assert_eq!(attempts[2].threads, Some(8));
+        assert_eq!(attempts[2].status, "failed (exit code 1)");
+        assert_eq!(res[1].attempts.len(), 3);
+
+        // A merger that cannot start is not retried
+        let missing = PearJob {
+            program: "/no/such/pear".to_string(),
+            ..jobs[1].clone()
+        };
+        let res =
+            run_jobs(&[missing], "Testing", 1, 2, &retry, |_, _| ()).unwrap();
+        assert_eq!(res[0].attempts.len(), 1);
+
+        // Nothing to double without a memory size
+        assert!(
+            get_args_from(["run_pear", "-Q", "x", "--retry_memory"]).is_err()
+        );
+        assert!(get_args_from([
+            "run_pear",
+            "-Q",
+            "x",
+            "--retry_memory",
+            "--memory",
+            "1G"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_timeout() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("45m"), Some(Duration::from_secs(2700)));
+        assert_eq!(parse_duration("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("2w"), None);
+        assert_eq!(parse_duration("m"), None);
+
+        // A merger that starts a child and never finishes
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("slow.sh");
+        fs::write(&script, "#!/bin/sh\nsleep 30 &\nsleep 30\n").unwrap();
+        Command::new("chmod")
+            .arg("+x")
+            .arg(&script)
+            .status()
+            .unwrap();
+
+        let job = PearJob {
+            program: script.display().to_string(),
+            timeout: Some(Duration::from_millis(200)),
+            ..job("a", Path::new(""))
+        };
+        let retry = RetryPolicy {
+            retries: 2,
+            ..RetryPolicy::default()
+        };
+
+        // Timeouts are retried but do not count to halt
+        let start = Instant::now();
+        let jobs = vec![job.clone(), job];
+        let res = run_jobs(&jobs, "Testing", 1, 1, &retry, |_, _| ()).unwrap();
+        let timed_out = JobStatus::TimedOut(Duration::from_millis(200));
+        assert_eq!(res[0].status, timed_out);
+        assert_eq!(res[0].attempts.len(), 3);
+        assert_eq!(res[1].status, timed_out);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Process groups are tracked for the signal handler while they run
+        let (a, b) = (track_group(-2), track_group(-3));
+        assert!(a.is_some() && b.is_some() && a != b);
+        assert_eq!(GROUPS[a.unwrap()].load(Ordering::SeqCst), -2);
+        untrack_group(a);
+        untrack_group(b);
+        assert!(GROUPS.iter().all(|g| g.load(Ordering::SeqCst) >= 0));
+    }
+
+    #[test]
+    fn test_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("noisy.sh");
+        fs::write(
+            &script,
+            "#!/bin/sh\necho merging\nfor i in 1 2 3; do echo $i >&2; done\n\
+             exit 3\n",
+        )
+        .unwrap();
+        Command::new("chmod")
+            .arg("+x")
+            .arg(&script)
+            .status()
+            .unwrap();
+
+        let prefix = dir.path().join("pear");
+        let job = PearJob {
+            program: script.display().to_string(),
+            log_prefix: Some(prefix.clone()),
+            ..job("a", Path::new(""))
+        };
+        assert_eq!(run_job(&job, 0).0, JobStatus::Failed(Some(3)));
+
+        let header = |attempt: u32| {
+            format!(
+                "# command: {}\n# exit code: 3\n# attempt: {}\n",
+                job, attempt
+            )
+        };
+        let log = dir.path().join("pear.log");
+        let err = dir.path().join("pear.err");
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!("{}merging\n", header(1))
+        );
+        assert_eq!(
+            fs::read_to_string(&err).unwrap(),
+            format!("{}1\n2\n3\n", header(1))
+        );
+        assert_eq!(tail_lines(&err, 2), ["2", "3"]);
+        assert_eq!(tail_lines(&err, 10).len(), 3);
+        assert!(tail_lines(&dir.path().join("missing.err"), 2).is_empty());
+
+        // Retries are appended, and a new job starts the logs afresh
+        let retry = RetryPolicy {
+            retries: 1,
+            ..RetryPolicy::default()
+        };
+        run_attempts(&job, &retry);
+        assert_eq!(
+            fs::read_to_string(&err).unwrap(),
+            format!("{}1\n2\n3\n{}1\n2\n3\n", header(1), header(2))
+        );
+        assert_eq!(tail_lines(&err, 10).len(), 3);
+
+        // A staged job logs the command it ran
+        let staged = PearJob {
+            staging: Some(dir.path().join("staging")),
+            ..job.clone()
+        };
+        run_job(&staged, 0);
+        let text = fs::read_to_string(&log).unwrap();
+        let ran = PearJob {
+            out_prefix: dir.path().join("staging").join("a"),
+            ..job.clone()
+        };
+        assert!(text.starts_with(&format!("# command: {}\n", ran)));
+
+        // A merger that cannot start still gets logs
+        let missing = PearJob {
+            program: "/no/such/pear".to_string(),
+            ..job.clone()
+        };
+        assert!(run_job(&missing, 0).0 != JobStatus::Success);
+        let text = fs::read_to_string(&err).unwrap();
+        assert!(text.contains("# exit code: none, failed to start"));
+
+        // Native jobs log their statistics as pear prints them
+        let fwd = dir.path().join("R1.fastq");
+        let rev = dir.path().join("R2.fastq");
+        fs::write(
+            &fwd,
+            "@r1\nACGTACGTACGTAAAAAAAAAAAA\n+\nIIIIIIIIIIIIIIIIIIIIIIII\n",
+        )
+        .unwrap();
+        fs::write(
+            &rev,
+            "@r1\nTTTTTTTTTTTTACGTACGTACGT\n+\nIIIIIIIIIIIIIIIIIIIIIIII\n",
+        )
+        .unwrap();
+        let native = PearJob {
+            engine: Engine::Native,
+            program: "native".to_string(),
+            forward: fwd,
+            reverse: rev,
+            out_prefix: dir.path().join("a"),
+            log_prefix: Some(dir.path().join("native")),
+            ..job
+        };
+        let (status, stats) = run_job(&native, 0);
+        assert_eq!(status, JobStatus::Success);
+        let text = fs::read_to_string(dir.path().join("native.log")).unwrap();
+        assert!(text.starts_with("# command: native -f "));
+        assert!(text.contains("# exit code: 0\n"));
+        assert_eq!(parse_pear_output(&text), stats);
+        assert!(text.contains("Assembled reads ...................: "));
+        let text = fs::read_to_string(dir.path().join("native.err")).unwrap();
+        assert_eq!(text.lines().count(), 3);
+    }
 }
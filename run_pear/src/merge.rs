@@ -0,0 +1,948 @@
+//! A native read merger used by `--engine native` in place of pear.
+//!
+//! The reverse read is reverse-complemented and slid along the forward
+//! read; the placement with the best score (see the `score` module) and
+//! at least `min_overlap` overlapping bases gives the insert. Overlapping
+//! bases are combined by `score::consensus`. Overhangs past the other
+//! read's start are adapter and are dropped, so inserts shorter than the
+//! reads are handled too.
+//!
+//! With `stitch`, pairs without an acceptable overlap are instead
+//! concatenated, the forward read, `stitch_spacer` Ns and the
+//! reverse-complemented reverse read, and written with the assembled
+//! reads. Pairs that overlap but fail the length limits are not
+//! stitched.
+//!
+//! As in pear, the best placement is only accepted if it is unlikely to
+//! score as well by chance. Under the null hypothesis that the reads do
+//! not overlap, each base pair matches with probability `q`, the sum of
+//! the squared base frequencies, so the score of a `k`-base overlap is
+//! `2X - k` with `X ~ Binomial(k, q)`, whatever the scoring method used
+//! to choose the placement. The p-value sums the chance of
+//! scoring at least as well over the overlaps from `min_overlap` up to
+//! the shorter read length, pear's test method 1. Method 2, pear's
+//! "maximal acceptance probability", is not implemented.
+
+use crate::fastq::{self, OwnedRecord, Record};
+use crate::score::{self, is_uncalled, Scorer};
+use crate::trim::Trimmer;
+use crate::MyResult;
+use std::path::Path;
+
+/// The highest quality character, "~"
+const MAX_QUAL_CHAR: u8 = 126;
+
+/// The p-value cut-offs pear accepts
+pub const P_VALUES: &[f32] = &[0.0001, 0.001, 0.01, 0.05, 1.];
+
+/// Base frequencies (A, C, G, T) when not estimated from the reads
+pub const UNIFORM_FREQS: [f64; 4] = [0.25; 4];
+
+// --------------------------------------------------
+#[derive(Debug, Clone, PartialEq)]
+pub struct Params {
+    pub min_overlap: usize,
+    pub min_assembly_length: usize,
+    /// 0 means no limit
+    pub max_assembly_length: usize,
+    /// Pairs with a larger proportion of Ns in either read are discarded
+    pub max_uncalled_base: f32,
+    /// Reads are trimmed before merging, see `Trimmer`
+    pub quality_threshold: u8,
+    pub min_trim_length: usize,
+    pub phred_base: u8,
+    /// One of `P_VALUES`, 1 disables the test
+    pub p_value: f32,
+    /// Estimate the base frequencies from the reads instead of 0.25 each
+    pub empirical_freqs: bool,
+    /// 1, 2 or 3, see `score::scorer`
+    pub score_method: u32,
+    /// The highest merged quality, 0 for no limit
+    pub cap: u8,
+    /// Call mismatches of two called bases "N"
+    pub nbase: bool,
+    /// Concatenate pairs that do not overlap
+    pub stitch: bool,
+    /// The number of Ns between stitched reads
+    pub stitch_spacer: usize,
+    /// Write unassembled and discarded reverse reads as sequenced rather
+    /// than reverse-complemented
+    pub keep_original: bool,
+}
+
+impl Params {
+    /// The settings labeled as pear prints them
+    pub fn settings(&self) -> Vec<(String, String)> {
+        let yes_no = |x: bool| if x { "YES" } else { "NO" };
+        vec![
+            ("PHRED", self.phred_base.to_string()),
+            (
+                "Using empirical frequencies",
+                yes_no(self.empirical_freqs).to_string(),
+            ),
+            ("Statistical method", "OES".to_string()),
+            (
+                "Maximum assembly length",
+                self.max_assembly_length.to_string(),
+            ),
+            (
+                "Minimum assembly length",
+                self.min_assembly_length.to_string(),
+            ),
+            ("p-value", format!("{:.6}", self.p_value)),
+            (
+                "Quality score threshold (trimming)",
+                self.quality_threshold.to_string(),
+            ),
+            (
+                "Minimum read size after trimming",
+                self.min_trim_length.to_string(),
+            ),
+            (
+                "Maximal ratio of uncalled bases",
+                format!("{:.6}", self.max_uncalled_base),
+            ),
+            ("Minimum overlap", self.min_overlap.to_string()),
+            (
+                "Scoring method",
+                score::method_name(self.score_method).to_string(),
+            ),
+            ("Quality score cap", self.cap.to_string()),
+            ("Uncalled mismatches", yes_no(self.nbase).to_string()),
+            ("Stitch", yes_no(self.stitch).to_string()),
+            ("Stitch spacer", self.stitch_spacer.to_string()),
+            ("Keep original", yes_no(self.keep_original).to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+}
+
+impl Params {
+    pub fn trimmer(&self) -> Trimmer {
+        Trimmer {
+            quality_threshold: self.quality_threshold,
+            min_length: self.min_trim_length,
+            phred_base: self.phred_base,
+        }
+    }
+}
+
+impl Default for Params {
+    /// pear's defaults
+    fn default() -> Params {
+        Params {
+            min_overlap: 10,
+            min_assembly_length: 50,
+            max_assembly_length: 0,
+            max_uncalled_base: 1.,
+            quality_threshold: 0,
+            min_trim_length: 1,
+            phred_base: 33,
+            p_value: 0.01,
+            empirical_freqs: true,
+            score_method: 2,
+            cap: 40,
+            nbase: false,
+            stitch: false,
+            stitch_spacer: 0,
+            keep_original: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Assembled(OwnedRecord),
+    Stitched(OwnedRecord),
+    Unassembled,
+    Discarded,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    /// Including the stitched pairs
+    pub assembled: u64,
+    pub stitched: u64,
+    pub unassembled: u64,
+    pub discarded: u64,
+}
+
+impl Counts {
+    pub fn total(&self) -> u64 {
+        self.assembled + self.unassembled + self.discarded
+    }
+}
+
+/// Merges pairs with one set of parameters, caching the null
+/// distributions of the statistical test
+#[derive(Debug)]
+pub struct Assembler {
+    params: Params,
+    scorer: Box<dyn Scorer>,
+    test: OverlapTest,
+}
+
+/// Where the reverse-complemented reverse read sits on the forward read
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Placement {
+    /// Offset of the reverse read's first base, negative if it starts
+    /// before the forward read
+    shift: isize,
+    overlap: usize,
+    score: f64,
+}
+
+/// The statistical test of an overlap's score
+#[derive(Debug)]
+struct OverlapTest {
+    /// The chance that two random bases match
+    match_prob: f64,
+    /// `tails[k][x]` is P(X >= x) for X ~ Binomial(k, match_prob)
+    tails: Vec<Vec<f64>>,
+}
+
+// --------------------------------------------------
+/// Merges two FASTQ files into "<out_prefix>.assembled.fastq",
+/// "<out_prefix>.unassembled.forward.fastq",
+/// "<out_prefix>.unassembled.reverse.fastq" and
+/// "<out_prefix>.discarded.fastq", the same layout pear writes. Reads
+/// are quality trimmed first.
+pub fn merge_files(
+    forward: &Path,
+    reverse: &Path,
+    out_prefix: &Path,
+    params: &Params,
+) -> MyResult<Counts> {
+    // Reads the input twice like pear when estimating the frequencies
+    let freqs = if params.empirical_freqs && params.p_value < 1. {
+        base_frequencies(&[forward, reverse])?
+    } else {
+        UNIFORM_FREQS
+    };
+    let mut assembler = Assembler::new(params.clone(), freqs);
+    let trimmer = params.trimmer();
+
+    let out = |suffix: &str| {
+        let mut name = out_prefix.as_os_str().to_owned();
+        name.push(suffix);
+        fastq::create(Path::new(&name))
+    };
+    let mut assembled = out(".assembled.fastq")?;
+    let mut unassembled_fwd = out(".unassembled.forward.fastq")?;
+    let mut unassembled_rev = out(".unassembled.reverse.fastq")?;
+    let mut discarded = out(".discarded.fastq")?;
+
+    let mut fwd = fastq::open(forward)?;
+    let mut rev = fastq::open(reverse)?;
+    let mut counts = Counts::default();
+
+    loop {
+        let (rec1, rec2) = match (fwd.next_record(), rev.next_record()) {
+            (None, None) => break,
+            (Some(rec1), Some(rec2)) => (rec1?, rec2?),
+            (Some(_), None) | (None, Some(_)) => {
+                let msg = format!(
+                    "\"{}\" and \"{}\" differ in number of records ({})",
+                    forward.display(),
+                    reverse.display(),
+                    counts.total() + 1
+                );
+                return Err(From::from(msg));
+            }
+        };
+
+        let reversed = |record: &dyn Record| {
+            if params.keep_original {
+                record.to_owned_record()
+            } else {
+                reverse_complement(record)
+            }
+        };
+
+        // Pairs with a mate too short after trimming are discarded whole
+        let (rec1, rec2) = match (trimmer.trim(&rec1), trimmer.trim(&rec2)) {
+            (Some(trimmed1), Some(trimmed2)) => (trimmed1, trimmed2),
+            _ => {
+                discarded.write(&rec1)?;
+                discarded.write(&reversed(&rec2))?;
+                counts.discarded += 1;
+                continue;
+            }
+        };
+
+        match assembler.merge_pair(&rec1, &rec2) {
+            Outcome::Assembled(merged) => {
+                assembled.write(&merged)?;
+                counts.assembled += 1;
+            }
+            Outcome::Stitched(stitched) => {
+                assembled.write(&stitched)?;
+                counts.assembled += 1;
+                counts.stitched += 1;
+            }
+            Outcome::Unassembled => {
+                unassembled_fwd.write(&rec1)?;
+                unassembled_rev.write(&reversed(&rec2))?;
+                counts.unassembled += 1;
+            }
+            Outcome::Discarded => {
+                discarded.write(&rec1)?;
+                discarded.write(&reversed(&rec2))?;
+                counts.discarded += 1;
+            }
+        }
+    }
+
+    for writer in &mut [assembled, unassembled_fwd, unassembled_rev, discarded]
+    {
+        writer.flush()?;
+    }
+
+    Ok(counts)
+}
+
+// --------------------------------------------------
+/// Counts the bases of the files to estimate the frequencies of A, C, G
+/// and T, or returns `UNIFORM_FREQS` if there are none
+pub fn base_frequencies(paths: &[&Path]) -> MyResult<[f64; 4]> {
+    let mut counts = [0u64; 4];
+    for path in paths {
+        let mut reader = fastq::open(path)?;
+        while let Some(record) = reader.next_record() {
+            for base in record?.seq() {
+                match base {
+                    b'A' | b'a' => counts[0] += 1,
+                    b'C' | b'c' => counts[1] += 1,
+                    b'G' | b'g' => counts[2] += 1,
+                    b'T' | b't' => counts[3] += 1,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return Ok(UNIFORM_FREQS);
+    }
+
+    let mut freqs = [0.; 4];
+    for (freq, count) in freqs.iter_mut().zip(&counts) {
+        *freq = *count as f64 / total as f64;
+    }
+    Ok(freqs)
+}
+
+// --------------------------------------------------
+impl Assembler {
+    pub fn new(params: Params, freqs: [f64; 4]) -> Assembler {
+        let scorer = score::scorer(params.score_method);
+        Assembler::with_scorer(params, freqs, scorer)
+    }
+
+    /// Uses `scorer` instead of the one for `params.score_method`
+    pub fn with_scorer(
+        params: Params,
+        freqs: [f64; 4],
+        scorer: Box<dyn Scorer>,
+    ) -> Assembler {
+        Assembler {
+            params,
+            scorer,
+            test: OverlapTest::new(freqs),
+        }
+    }
+
+    /// Merges one pair, the reverse read as sequenced
+    pub fn merge_pair<A: Record, B: Record>(
+        &mut self,
+        forward: &A,
+        reverse: &B,
+    ) -> Outcome {
+        let params = &self.params;
+        if too_many_uncalled(forward.seq(), params.max_uncalled_base)
+            || too_many_uncalled(reverse.seq(), params.max_uncalled_base)
+        {
+            return Outcome::Discarded;
+        }
+
+        let base = params.phred_base;
+        let (seq1, qual1) = (forward.seq(), quals(forward.qual(), base));
+        let seq2: Vec<u8> =
+            reverse.seq().iter().rev().map(|&b| complement(b)).collect();
+        let qual2: Vec<u8> =
+            quals(reverse.qual(), base).into_iter().rev().collect();
+
+        let placement = match self.find_overlap((seq1, &qual1), (&seq2, &qual2))
+        {
+            Some(placement) => placement,
+            _ if self.params.stitch => {
+                return Outcome::Stitched(self.stitch(forward, reverse))
+            }
+            _ => return Outcome::Unassembled,
+        };
+        let params = &self.params;
+
+        let length = (placement.shift + seq2.len() as isize) as usize;
+        if length < params.min_assembly_length
+            || (params.max_assembly_length > 0
+                && length > params.max_assembly_length)
+        {
+            return Outcome::Unassembled;
+        }
+
+        let max_qual = match params.cap {
+            0 => MAX_QUAL_CHAR - base,
+            cap => cap.min(MAX_QUAL_CHAR - base),
+        };
+
+        let mut seq = Vec::with_capacity(length);
+        let mut qual = Vec::with_capacity(length);
+        for i in 0..length {
+            let j = i as isize - placement.shift;
+            let from_fwd = (i < seq1.len()).then(|| (seq1[i], qual1[i]));
+            let from_rev =
+                (j >= 0).then(|| (seq2[j as usize], qual2[j as usize]));
+            let (b, q) = match (from_fwd, from_rev) {
+                (Some(x), Some(y)) => {
+                    score::consensus(x, y, max_qual, params.nbase)
+                }
+                (Some(x), None) | (None, Some(x)) => x,
+                (None, None) => unreachable!("position outside both reads"),
+            };
+            seq.push(b);
+            qual.push(q + base);
+        }
+
+        Outcome::Assembled(OwnedRecord {
+            head: forward.head().to_vec(),
+            seq,
+            qual,
+        })
+    }
+
+    /// The best placement passing the statistical test
+    fn find_overlap(
+        &mut self,
+        (seq1, qual1): (&[u8], &[u8]),
+        (seq2, qual2): (&[u8], &[u8]),
+    ) -> Option<Placement> {
+        let params = &self.params;
+        let placement = best_placement(
+            (seq1, qual1),
+            (seq2, qual2),
+            params.min_overlap,
+            self.scorer.as_ref(),
+        )?;
+
+        if params.p_value < 1. {
+            let end = seq1.len().min(seq2.len());
+            let matches = simple_score(seq1, seq2, placement.shift);
+            let p = self.test.p_value(matches, params.min_overlap, end);
+            if p >= params.p_value as f64 {
+                return None;
+            }
+        }
+
+        Some(placement)
+    }
+
+    /// The forward read, the spacer and the reverse-complemented reverse
+    /// read
+    fn stitch<A: Record, B: Record>(
+        &self,
+        forward: &A,
+        reverse: &B,
+    ) -> OwnedRecord {
+        let spacer = self.params.stitch_spacer;
+        let reverse = reverse_complement(reverse);
+        let mut seq = forward.seq().to_vec();
+        seq.extend(std::iter::repeat_n(b'N', spacer));
+        seq.extend(reverse.seq);
+        let mut qual = forward.qual().to_vec();
+        qual.extend(std::iter::repeat_n(self.params.phred_base, spacer));
+        qual.extend(reverse.qual);
+
+        OwnedRecord {
+            head: forward.head().to_vec(),
+            seq,
+            qual,
+        }
+    }
+}
+
+// --------------------------------------------------
+/// The record with its sequence reverse-complemented and its qualities
+/// reversed
+pub fn reverse_complement<R: Record + ?Sized>(record: &R) -> OwnedRecord {
+    OwnedRecord {
+        head: record.head().to_vec(),
+        seq: record.seq().iter().rev().map(|&b| complement(b)).collect(),
+        qual: record.qual().iter().rev().copied().collect(),
+    }
+}
+
+// --------------------------------------------------
+/// The placement with the highest positive score, preferring the longer
+/// overlap on ties. Pairs with an uncalled base score 0.
+fn best_placement(
+    (seq1, qual1): (&[u8], &[u8]),
+    (seq2, qual2): (&[u8], &[u8]),
+    min_overlap: usize,
+    scorer: &dyn Scorer,
+) -> Option<Placement> {
+    let (len1, len2) = (seq1.len() as isize, seq2.len() as isize);
+    let min_overlap = min_overlap.max(1) as isize;
+    let mut best: Option<Placement> = None;
+
+    for shift in (min_overlap - len2)..=(len1 - min_overlap) {
+        let start = shift.max(0);
+        let end = len1.min(shift + len2);
+        let overlap = (end - start) as usize;
+
+        let score: f64 = (start as usize..end as usize)
+            .map(|i| {
+                let j = (i as isize - shift) as usize;
+                let (a, b) = (seq1[i], seq2[j]);
+                if is_uncalled(a) || is_uncalled(b) {
+                    0.
+                } else {
+                    scorer.score(a, qual1[i], b, qual2[j])
+                }
+            })
+            .sum();
+
+        let better = match &best {
+            Some(b) => {
+                score > b.score || (score == b.score && overlap > b.overlap)
+            }
+            _ => true,
+        };
+        if score > 0. && better {
+            best = Some(Placement {
+                shift,
+                overlap,
+                score,
+            });
+        }
+    }
+
+    best
+}
+
+// --------------------------------------------------
+/// The +1/-1 score of a placement used by the statistical test
+fn simple_score(seq1: &[u8], seq2: &[u8], shift: isize) -> i64 {
+    let start = shift.max(0);
+    let end = (seq1.len() as isize).min(shift + seq2.len() as isize);
+    (start..end)
+        .map(|i| {
+            let (a, b) = (seq1[i as usize], seq2[(i - shift) as usize]);
+            if is_uncalled(a) || is_uncalled(b) {
+                0
+            } else if a.eq_ignore_ascii_case(&b) {
+                1
+            } else {
+                -1
+            }
+        })
+        .sum()
+}
+
+// --------------------------------------------------
+impl OverlapTest {
+    fn new(freqs: [f64; 4]) -> OverlapTest {
+        let match_prob: f64 = freqs.iter().map(|f| f * f).sum();
+        OverlapTest {
+            match_prob: match_prob.clamp(1e-6, 1. - 1e-6),
+            tails: vec![],
+        }
+    }
+
+    /// The chance that non-overlapping reads score at least `score` for
+    /// some overlap of `start` to `end` bases.
+    ///
+    /// This approximates pear's test rather than reproducing it. The
+    /// per-overlap tails are summed, a union bound on the chance that
+    /// any one overlap scores as well, instead of the exact distribution
+    /// of the best score. The score is the simple match count of the
+    /// chosen placement, not the quality-aware score that chose it. The
+    /// test is slightly conservative as a result.
+    fn p_value(&mut self, score: i64, start: usize, end: usize) -> f64 {
+        let mut p = 0.;
+        for k in start.max(1)..=end {
+            // 2x - k >= score
+            let x = (score + k as i64 + 1).div_euclid(2).max(0) as usize;
+            p += self.tail(k, x);
+            if p >= 1. {
+                return 1.;
+            }
+        }
+        p
+    }
+
+    /// P(X >= x) for X ~ Binomial(k, match_prob)
+    fn tail(&mut self, k: usize, x: usize) -> f64 {
+        while self.tails.len() <= k {
+            let n = self.tails.len();
+            self.tails.push(binomial_tails(n, self.match_prob));
+        }
+        self.tails[k].get(x).copied().unwrap_or(0.)
+    }
+}
+
+/// P(X >= x) for x in 0..=n and X ~ Binomial(n, p), computed in log
+/// space so long overlaps do not underflow
+fn binomial_tails(n: usize, p: f64) -> Vec<f64> {
+    let (ln_p, ln_q) = (p.ln(), (1. - p).ln());
+    let mut ln_pmf = Vec::with_capacity(n + 1);
+    let mut ln_choose = 0.;
+    for x in 0..=n {
+        if x > 0 {
+            ln_choose += ((n - x + 1) as f64 / x as f64).ln();
+        }
+        ln_pmf.push(ln_choose + x as f64 * ln_p + (n - x) as f64 * ln_q);
+    }
+
+    let mut tails = vec![0.; n + 1];
+    let mut sum = 0.;
+    for x in (0..=n).rev() {
+        sum += ln_pmf[x].exp();
+        tails[x] = sum.min(1.);
+    }
+    tails
+}
+
+// --------------------------------------------------
+fn quals(qual: &[u8], base: u8) -> Vec<u8> {
+    qual.iter().map(|q| q.saturating_sub(base)).collect()
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        _ => b'N',
+    }
+}
+
+fn too_many_uncalled(seq: &[u8], max_uncalled_base: f32) -> bool {
+    if seq.is_empty() {
+        return false;
+    }
+    let num_uncalled = seq.iter().filter(|&&b| is_uncalled(b)).count();
+    num_uncalled as f32 / seq.len() as f32 > max_uncalled_base
+}
+
+// --------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: &str, qual: &str) -> OwnedRecord {
+        OwnedRecord {
+            head: b"r1".to_vec(),
+            seq: seq.as_bytes().to_vec(),
+            qual: qual.as_bytes().to_vec(),
+        }
+    }
+
+    fn revcomp(seq: &str) -> String {
+        seq.bytes().rev().map(|b| complement(b) as char).collect()
+    }
+
+    fn params() -> Params {
+        Params {
+            min_overlap: 4,
+            min_assembly_length: 1,
+            p_value: 1.,
+            ..Params::default()
+        }
+    }
+
+    fn merge_pair(
+        fwd: &OwnedRecord,
+        rev: &OwnedRecord,
+        params: Params,
+    ) -> Outcome {
+        Assembler::new(params, UNIFORM_FREQS).merge_pair(fwd, rev)
+    }
+
+    #[test]
+    fn test_merge_pair() {
+        // A 16-base insert read 10 bases from each end
+        let insert = "ACGTTGCAAGGCTTAC";
+        let fwd = record(&insert[..10], "IIIIIIIIII");
+        let rev = record(&revcomp(&insert[6..]), "5555555555");
+
+        match merge_pair(&fwd, &rev, params()) {
+            Outcome::Assembled(merged) => {
+                assert_eq!(merged.seq, insert.as_bytes());
+                // 40 + 20 capped at 40 in the overlap
+                assert_eq!(merged.qual, b"IIIIIIIIII555555");
+            }
+            res => panic!("not assembled: {:?}", res),
+        }
+
+        // No cap gives 40 + 20 = 60, "]"
+        let uncapped = Params { cap: 0, ..params() };
+        match merge_pair(&fwd, &rev, uncapped) {
+            Outcome::Assembled(merged) => {
+                assert_eq!(merged.qual, b"IIIIII]]]]555555")
+            }
+            res => panic!("not assembled: {:?}", res),
+        }
+
+        // A mismatch in the overlap, "N" with --nbase
+        let mut fwd_snp = fwd.clone();
+        fwd_snp.seq[8] = b'T';
+        let nbase = Params {
+            nbase: true,
+            ..params()
+        };
+        match merge_pair(&fwd_snp, &rev, params()) {
+            Outcome::Assembled(merged) => {
+                assert_eq!(merged.seq, b"ACGTTGCATGGCTTAC");
+                assert_eq!(merged.qual[8], b'5');
+            }
+            res => panic!("not assembled: {:?}", res),
+        }
+        match merge_pair(&fwd_snp, &rev, nbase) {
+            Outcome::Assembled(merged) => {
+                assert_eq!(merged.seq, b"ACGTTGCANGGCTTAC");
+                assert_eq!(merged.qual[8], b'I');
+            }
+            res => panic!("not assembled: {:?}", res),
+        }
+
+        // Too short an overlap
+        let strict = Params {
+            min_overlap: 5,
+            ..params()
+        };
+        assert_eq!(merge_pair(&fwd, &rev, strict), Outcome::Unassembled);
+
+        // Assembly length limits
+        let short = Params {
+            max_assembly_length: 15,
+            ..params()
+        };
+        assert_eq!(merge_pair(&fwd, &rev, short), Outcome::Unassembled);
+        let long = Params {
+            min_assembly_length: 17,
+            ..params()
+        };
+        assert_eq!(merge_pair(&fwd, &rev, long), Outcome::Unassembled);
+
+        // Uncalled bases
+        let ns = record("ACGTNNNNNN", "IIIIIIIIII");
+        let half = Params {
+            max_uncalled_base: 0.5,
+            ..params()
+        };
+        assert_eq!(merge_pair(&ns, &rev, half), Outcome::Discarded);
+    }
+
+    #[test]
+    fn test_merge_pair_short_insert() {
+        // Both reads run past the 8-base insert into the adapters
+        let insert = "ACGTTGCA";
+        let fwd = record(&format!("{}GGGG", insert), "IIIIIIIIIIII");
+        let rev = record(&format!("{}CCCC", revcomp(insert)), "IIIIIIIIIIII");
+
+        match merge_pair(&fwd, &rev, params()) {
+            Outcome::Assembled(merged) => {
+                assert_eq!(merged.seq, insert.as_bytes())
+            }
+            res => panic!("not assembled: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_overlap_test() {
+        let mut test = OverlapTest::new(UNIFORM_FREQS);
+        assert!((test.tail(4, 0) - 1.).abs() < 1e-12);
+        assert!((test.tail(4, 4) - 0.25f64.powi(4)).abs() < 1e-12);
+        assert_eq!(test.tail(4, 5), 0.);
+        // One overlap of 2 scoring 2 (both match) or better
+        assert!((test.p_value(2, 2, 2) - 0.0625).abs() < 1e-12);
+        // Long overlaps do not underflow
+        assert!(test.tail(2000, 1) > 0.99);
+        // More overlaps to choose from make a score likelier by chance
+        assert!(test.p_value(8, 20, 60) < test.p_value(8, 10, 60));
+
+        // A 100-base insert read 60 bases from each end, a 20-base overlap
+        let insert = random_seq(100, 1);
+        let fwd = record(&insert[..60], &"I".repeat(60));
+        let rev = record(&revcomp(&insert[40..]), &"I".repeat(60));
+        let other = record(&random_seq(60, 3), &"I".repeat(60));
+        let with = |p_value: f32| Params {
+            p_value,
+            min_overlap: 5,
+            ..params()
+        };
+
+        for &p_value in P_VALUES {
+            assert_ne!(
+                merge_pair(&fwd, &rev, with(p_value)),
+                Outcome::Unassembled
+            );
+        }
+
+        // Unrelated mates only assemble by chance without the test
+        assert_ne!(merge_pair(&fwd, &other, with(1.)), Outcome::Unassembled);
+        assert_eq!(merge_pair(&fwd, &other, with(0.01)), Outcome::Unassembled);
+    }
+
+    #[test]
+    fn test_overlap_test_false_merges() {
+        // Unrelated 100-base mates with the default parameters
+        let qual = "I".repeat(100);
+        let pairs: Vec<(OwnedRecord, OwnedRecord)> = (0..300)
+            .map(|i| {
+                (
+                    record(&random_seq(100, 2 * i + 11), &qual),
+                    record(&random_seq(100, 2 * i + 12), &qual),
+                )
+            })
+            .collect();
+        let rate = |p_value: f32| {
+            let mut assembler = Assembler::new(
+                Params {
+                    p_value,
+                    ..Params::default()
+                },
+                UNIFORM_FREQS,
+            );
+            let merged = pairs
+                .iter()
+                .filter(|(fwd, rev)| {
+                    assembler.merge_pair(fwd, rev) != Outcome::Unassembled
+                })
+                .count();
+            merged as f64 / pairs.len() as f64
+        };
+
+        // About 1% at p 0.01 and 20% without the test
+        let (tested, untested) = (rate(0.01), rate(1.));
+        assert!(tested <= 0.03, "p 0.01 merged {}", tested);
+        assert!(untested >= 0.1, "no test merged {}", untested);
+    }
+
+    /// A reproducible pseudo-random sequence
+    fn random_seq(len: usize, seed: u64) -> String {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                ['A', 'C', 'G', 'T'][(state >> 62) as usize]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let insert = "ACGTTGCAAGGCTTAC";
+        let write = |name: &str, text: String| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, text).unwrap();
+            path
+        };
+        let r1 = write(
+            "R1.fq",
+            format!(
+                "@a/1\n{}\n+\nIIIIIIIIII\n@b/1\nAAAAAA\n+\nIIIIII\n",
+                &insert[..10]
+            ),
+        );
+        let r2 = write(
+            "R2.fq",
+            format!(
+                "@a/2\n{}\n+\nIIIIIIIIII\n@b/2\nGGGGGG\n+\nIIII55\n",
+                revcomp(&insert[6..])
+            ),
+        );
+
+        let prefix = dir.path().join("out");
+        let counts = merge_files(&r1, &r2, &prefix, &params()).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                assembled: 1,
+                stitched: 0,
+                unassembled: 1,
+                discarded: 0
+            }
+        );
+
+        let read = |suffix: &str| {
+            std::fs::read_to_string(dir.path().join(format!("out{}", suffix)))
+                .unwrap()
+        };
+        assert_eq!(
+            read(".assembled.fastq"),
+            format!("@a/1\n{}\n+\nIIIIIIIIIIIIIIII\n", insert)
+        );
+        assert_eq!(
+            read(".unassembled.forward.fastq"),
+            "@b/1\nAAAAAA\n+\nIIIIII\n"
+        );
+        // Reverse-complemented unless keeping the original
+        assert_eq!(
+            read(".unassembled.reverse.fastq"),
+            "@b/2\nCCCCCC\n+\n55IIII\n"
+        );
+        assert_eq!(read(".discarded.fastq"), "");
+
+        let keep = Params {
+            keep_original: true,
+            ..params()
+        };
+        merge_files(&r1, &r2, &prefix, &keep).unwrap();
+        assert_eq!(
+            read(".unassembled.reverse.fastq"),
+            "@b/2\nGGGGGG\n+\nIIII55\n"
+        );
+
+        // Stitched pairs go with the assembled reads
+        let stitch = Params {
+            stitch: true,
+            stitch_spacer: 2,
+            ..params()
+        };
+        let counts = merge_files(&r1, &r2, &prefix, &stitch).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                assembled: 2,
+                stitched: 1,
+                unassembled: 0,
+                discarded: 0
+            }
+        );
+        assert_eq!(
+            read(".assembled.fastq"),
+            format!(
+                "@a/1\n{}\n+\nIIIIIIIIIIIIIIII\n\
+                 @b/1\nAAAAAANNCCCCCC\n+\nIIIIII!!55IIII\n",
+                insert
+            )
+        );
+        assert_eq!(read(".unassembled.forward.fastq"), "");
+        assert_eq!(read(".unassembled.reverse.fastq"), "");
+
+        let short = write("short.fq", "@a/2\nACGT\n+\nIIII\n".to_string());
+        assert!(merge_files(&r1, &short, &prefix, &params()).is_err());
+    }
+}
@@ -0,0 +1,503 @@
+//! The external read mergers selected by `--merger`.
+//!
+//! Each `Merger` turns a pair, an output prefix and the neutral
+//! `PearOption`s into a command line and knows where the tool writes its
+//! merged and unmerged reads. Options without an equivalent in a tool
+//! are left out of its command.
+
+use crate::fastq;
+use crate::{MergeStats, MyResult, PearOption};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// --------------------------------------------------
+pub(crate) trait Merger: fmt::Debug + Sync {
+    /// The name given to `--merger`
+    fn name(&self) -> &'static str;
+
+    /// The program run unless `--pear` names another
+    fn program(&self) -> &'static str;
+
+    /// The arguments for one option, or `None` if the tool has none
+    fn option_args(&self, option: &PearOption) -> Option<Vec<String>>;
+
+    /// The full argument vector
+    fn args(
+        &self,
+        forward: &Path,
+        reverse: &Path,
+        out_prefix: &Path,
+        options: &[PearOption],
+    ) -> Vec<OsString>;
+
+    /// Where the reads end up for an output prefix
+    fn outputs(&self, out_prefix: &Path) -> Outputs;
+
+    /// The merge statistics, by default from counting the output reads
+    fn stats(&self, _stdout: &str, out_prefix: &Path) -> Option<MergeStats> {
+        let outputs = self.outputs(out_prefix);
+        let assembled = count_records(&outputs.assembled).ok()?;
+        let unassembled = count_records(&outputs.unassembled_forward).ok()?;
+        let discarded = match &outputs.discarded {
+            Some(path) => count_records(path).ok()?,
+            _ => 0,
+        };
+        Some(MergeStats::from_counts(assembled, discarded, unassembled))
+    }
+
+    /// The arguments for all the options the tool supports
+    fn options_args(&self, options: &[PearOption]) -> Vec<OsString> {
+        options
+            .iter()
+            .filter_map(|opt| self.option_args(opt))
+            .flatten()
+            .map(OsString::from)
+            .collect()
+    }
+}
+
+/// The files a merger writes
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Outputs {
+    pub assembled: PathBuf,
+    pub unassembled_forward: PathBuf,
+    pub unassembled_reverse: PathBuf,
+    pub discarded: Option<PathBuf>,
+}
+
+impl Outputs {
+    /// Checks that every file is complete FASTQ, that there are as many
+    /// forward as reverse unmerged reads and, given the statistics, that
+    /// the merged reads are all there
+    pub fn check(&self, stats: Option<&MergeStats>) -> MyResult<()> {
+        let count = |path: &Path| {
+            count_records(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))
+        };
+
+        let assembled = count(&self.assembled)?;
+        let forward = count(&self.unassembled_forward)?;
+        let reverse = count(&self.unassembled_reverse)?;
+        if let Some(discarded) = &self.discarded {
+            count(discarded)?;
+        }
+
+        if forward != reverse {
+            return Err(From::from(format!(
+                "{} forward but {} reverse unassembled reads",
+                forward, reverse
+            )));
+        }
+
+        match stats {
+            Some(stats) if stats.assembled != assembled => {
+                Err(From::from(format!(
+                    "{} assembled reads reported but {} written",
+                    stats.assembled, assembled
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Pear;
+
+#[derive(Debug)]
+pub(crate) struct Flash;
+
+#[derive(Debug)]
+pub(crate) struct NgMerge;
+
+#[derive(Debug)]
+pub(crate) struct Vsearch;
+
+pub(crate) const MERGERS: &[&dyn Merger] = &[&Pear, &Flash, &NgMerge, &Vsearch];
+
+/// The merger for a `--merger` name
+pub(crate) fn find(name: &str) -> Option<&'static dyn Merger> {
+    MERGERS
+        .iter()
+        .copied()
+        .find(|m| m.name().eq_ignore_ascii_case(name))
+}
+
+// --------------------------------------------------
+impl Merger for Pear {
+    fn name(&self) -> &'static str {
+        "pear"
+    }
+
+    fn program(&self) -> &'static str {
+        "pear"
+    }
+
+    fn option_args(&self, option: &PearOption) -> Option<Vec<String>> {
+        let (flag, value) = match option {
+            PearOption::PValue(x) => ("-p", Some(x.to_string())),
+            PearOption::MinOverlap(x) => ("-v", Some(x.to_string())),
+            PearOption::MaxAssemblyLength(x) => ("-m", Some(x.to_string())),
+            PearOption::MinAssemblyLength(x) => ("-n", Some(x.to_string())),
+            PearOption::MinTrimLength(x) => ("-t", Some(x.to_string())),
+            PearOption::QualityThreshold(x) => ("-q", Some(x.to_string())),
+            PearOption::MaxUncalledBase(x) => ("-u", Some(x.to_string())),
+            PearOption::TestMethod(x) => ("-g", Some(x.to_string())),
+            PearOption::EmpiricalFreqs => ("-e", None),
+            PearOption::ScoreMethod(x) => ("-s", Some(x.to_string())),
+            PearOption::PhredBase(x) => ("-b", Some(x.to_string())),
+            PearOption::Memory(x) => ("-y", Some(x.to_string())),
+            PearOption::Cap(x) => ("-c", Some(x.to_string())),
+            PearOption::Threads(x) => ("-j", Some(x.to_string())),
+            PearOption::Nbase => ("-z", None),
+            PearOption::KeepOriginal => ("-k", None),
+            PearOption::Stitch => ("-i", None),
+            PearOption::StitchSpacer(_) => return None,
+        };
+
+        let mut args = vec![flag.to_string()];
+        if let Some(value) = value {
+            args.push(value);
+        }
+        Some(args)
+    }
+
+    fn args(
+        &self,
+        forward: &Path,
+        reverse: &Path,
+        out_prefix: &Path,
+        options: &[PearOption],
+    ) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            "-f".into(),
+            forward.into(),
+            "-r".into(),
+            reverse.into(),
+            "-o".into(),
+            out_prefix.into(),
+        ];
+        args.extend(self.options_args(options));
+        args
+    }
+
+    fn outputs(&self, out_prefix: &Path) -> Outputs {
+        Outputs {
+            assembled: with_suffix(out_prefix, ".assembled.fastq"),
+            unassembled_forward: with_suffix(
+                out_prefix,
+                ".unassembled.forward.fastq",
+            ),
+            unassembled_reverse: with_suffix(
+                out_prefix,
+                ".unassembled.reverse.fastq",
+            ),
+            discarded: Some(with_suffix(out_prefix, ".discarded.fastq")),
+        }
+    }
+
+    /// Parsed from pear's STDOUT
+    fn stats(&self, stdout: &str, _out_prefix: &Path) -> Option<MergeStats> {
+        crate::parse_pear_output(stdout)
+    }
+}
+
+// --------------------------------------------------
+/// FLASH writes "<name>.extendedFrags.fastq" and
+/// "<name>.notCombined_[12].fastq" into the directory given by "-d"
+impl Merger for Flash {
+    fn name(&self) -> &'static str {
+        "flash"
+    }
+
+    fn program(&self) -> &'static str {
+        "flash"
+    }
+
+    fn option_args(&self, option: &PearOption) -> Option<Vec<String>> {
+        let (flag, value) = match option {
+            PearOption::MinOverlap(x) => ("-m", x.to_string()),
+            PearOption::PhredBase(x) => ("-p", x.to_string()),
+            PearOption::Threads(x) => ("-t", x.to_string()),
+            _ => return None,
+        };
+        Some(vec![flag.to_string(), value])
+    }
+
+    fn args(
+        &self,
+        forward: &Path,
+        reverse: &Path,
+        out_prefix: &Path,
+        options: &[PearOption],
+    ) -> Vec<OsString> {
+        let dir = match out_prefix.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = out_prefix.file_name().unwrap_or_default();
+
+        let mut args: Vec<OsString> =
+            vec!["-d".into(), dir.into(), "-o".into(), name.into()];
+        args.extend(self.options_args(options));
+        args.push(forward.into());
+        args.push(reverse.into());
+        args
+    }
+
+    fn outputs(&self, out_prefix: &Path) -> Outputs {
+        Outputs {
+            assembled: with_suffix(out_prefix, ".extendedFrags.fastq"),
+            unassembled_forward: with_suffix(
+                out_prefix,
+                ".notCombined_1.fastq",
+            ),
+            unassembled_reverse: with_suffix(
+                out_prefix,
+                ".notCombined_2.fastq",
+            ),
+            discarded: None,
+        }
+    }
+}
+
+// --------------------------------------------------
+/// NGmerge writes the merged reads to "-o" and the others to
+/// "<-f>_[12].fastq"
+impl Merger for NgMerge {
+    fn name(&self) -> &'static str {
+        "ngmerge"
+    }
+
+    fn program(&self) -> &'static str {
+        "NGmerge"
+    }
+
+    fn option_args(&self, option: &PearOption) -> Option<Vec<String>> {
+        let (flag, value) = match option {
+            PearOption::MinOverlap(x) => ("-m", x.to_string()),
+            PearOption::PhredBase(x) => ("-q", x.to_string()),
+            PearOption::Cap(x) => ("-u", x.to_string()),
+            PearOption::Threads(x) => ("-n", x.to_string()),
+            _ => return None,
+        };
+        Some(vec![flag.to_string(), value])
+    }
+
+    fn args(
+        &self,
+        forward: &Path,
+        reverse: &Path,
+        out_prefix: &Path,
+        options: &[PearOption],
+    ) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            "-1".into(),
+            forward.into(),
+            "-2".into(),
+            reverse.into(),
+            "-o".into(),
+            with_suffix(out_prefix, ".assembled.fastq").into(),
+            "-f".into(),
+            with_suffix(out_prefix, ".unassembled").into(),
+        ];
+        args.extend(self.options_args(options));
+        args
+    }
+
+    fn outputs(&self, out_prefix: &Path) -> Outputs {
+        Outputs {
+            assembled: with_suffix(out_prefix, ".assembled.fastq"),
+            unassembled_forward: with_suffix(
+                out_prefix,
+                ".unassembled_1.fastq",
+            ),
+            unassembled_reverse: with_suffix(
+                out_prefix,
+                ".unassembled_2.fastq",
+            ),
+            discarded: None,
+        }
+    }
+}
+
+// --------------------------------------------------
+/// `vsearch --fastq_mergepairs` with pear's file names
+impl Merger for Vsearch {
+    fn name(&self) -> &'static str {
+        "vsearch"
+    }
+
+    fn program(&self) -> &'static str {
+        "vsearch"
+    }
+
+    fn option_args(&self, option: &PearOption) -> Option<Vec<String>> {
+        let (flag, value) = match option {
+            PearOption::MinOverlap(x) => ("--fastq_minovlen", x.to_string()),
+            PearOption::MinAssemblyLength(x) => {
+                ("--fastq_minmergelen", x.to_string())
+            }
+            // 0 is no limit for pear
+            PearOption::MaxAssemblyLength(0) => return None,
+            PearOption::MaxAssemblyLength(x) => {
+                ("--fastq_maxmergelen", x.to_string())
+            }
+            PearOption::PhredBase(x) => ("--fastq_ascii", x.to_string()),
+            PearOption::Cap(x) => ("--fastq_qmaxout", x.to_string()),
+            PearOption::Threads(x) => ("--threads", x.to_string()),
+            _ => return None,
+        };
+        Some(vec![flag.to_string(), value])
+    }
+
+    fn args(
+        &self,
+        forward: &Path,
+        reverse: &Path,
+        out_prefix: &Path,
+        options: &[PearOption],
+    ) -> Vec<OsString> {
+        let outputs = self.outputs(out_prefix);
+        let mut args: Vec<OsString> = vec![
+            "--fastq_mergepairs".into(),
+            forward.into(),
+            "--reverse".into(),
+            reverse.into(),
+            "--fastqout".into(),
+            outputs.assembled.into(),
+            "--fastqout_notmerged_fwd".into(),
+            outputs.unassembled_forward.into(),
+            "--fastqout_notmerged_rev".into(),
+            outputs.unassembled_reverse.into(),
+        ];
+        args.extend(self.options_args(options));
+        args
+    }
+
+    fn outputs(&self, out_prefix: &Path) -> Outputs {
+        Outputs {
+            discarded: None,
+            ..Pear.outputs(out_prefix)
+        }
+    }
+}
+
+// --------------------------------------------------
+fn with_suffix(prefix: &Path, suffix: &str) -> PathBuf {
+    let mut name = prefix.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// The number of records in a (possibly compressed) FASTQ file
+fn count_records(path: &Path) -> MyResult<u64> {
+    let mut reader = fastq::open(path)?;
+    let mut count = 0;
+    while let Some(record) = reader.next_record() {
+        record?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// --------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(merger: &dyn Merger, options: &[PearOption]) -> String {
+        merger
+            .args(
+                Path::new("R1.fq"),
+                Path::new("R2.fq"),
+                Path::new("out/s1/s1"),
+                options,
+            )
+            .iter()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find("pear").map(|m| m.name()), Some("pear"));
+        assert_eq!(find("NGmerge").map(|m| m.program()), Some("NGmerge"));
+        assert!(find("bbmerge").is_none());
+    }
+
+    #[test]
+    fn test_args() {
+        let options = vec![
+            PearOption::MinOverlap(20),
+            PearOption::MaxAssemblyLength(0),
+            PearOption::Threads(4),
+            PearOption::Stitch,
+            PearOption::StitchSpacer(2),
+        ];
+
+        assert_eq!(
+            args(&Pear, &options),
+            "-f R1.fq -r R2.fq -o out/s1/s1 -v 20 -m 0 -j 4 -i"
+        );
+        assert_eq!(
+            args(&Flash, &options),
+            "-d out/s1 -o s1 -m 20 -t 4 R1.fq R2.fq"
+        );
+        assert_eq!(
+            args(&NgMerge, &options),
+            "-1 R1.fq -2 R2.fq -o out/s1/s1.assembled.fastq \
+             -f out/s1/s1.unassembled -m 20 -n 4"
+        );
+        assert_eq!(
+            args(&Vsearch, &options),
+            "--fastq_mergepairs R1.fq --reverse R2.fq \
+             --fastqout out/s1/s1.assembled.fastq \
+             --fastqout_notmerged_fwd out/s1/s1.unassembled.forward.fastq \
+             --fastqout_notmerged_rev out/s1/s1.unassembled.reverse.fastq \
+             --fastq_minovlen 20 --threads 4"
+        );
+
+        assert_eq!(Flash.option_args(&PearOption::Stitch), None);
+        assert_eq!(
+            Vsearch.option_args(&PearOption::MaxAssemblyLength(300)),
+            Some(vec!["--fastq_maxmergelen".to_string(), "300".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("s1");
+        let outputs = Flash.outputs(&prefix);
+        let rec = "@r\nACGT\n+\nIIII\n";
+        std::fs::write(&outputs.assembled, rec.repeat(3)).unwrap();
+        std::fs::write(&outputs.unassembled_forward, rec).unwrap();
+        std::fs::write(&outputs.unassembled_reverse, rec).unwrap();
+
+        let stats = Flash.stats("", &prefix).unwrap();
+        assert_eq!(stats.assembled, 3);
+        assert_eq!(stats.unassembled, 1);
+        assert_eq!(stats.discarded, 0);
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.assembled_pct, 75.);
+
+        assert!(outputs.check(Some(&stats)).is_ok());
+        assert!(outputs.check(None).is_ok());
+
+        // A short count, an unmatched mate and a truncated record
+        let short = MergeStats::from_counts(4, 0, 1);
+        assert!(outputs.check(Some(&short)).is_err());
+        std::fs::write(&outputs.unassembled_reverse, rec.repeat(2)).unwrap();
+        assert!(outputs.check(None).is_err());
+        std::fs::write(&outputs.unassembled_reverse, "@r\nACGT\n+\nII")
+            .unwrap();
+        assert!(outputs.check(None).is_err());
+
+        // Missing outputs
+        assert!(NgMerge.stats("", &prefix).is_none());
+        assert!(NgMerge.outputs(&prefix).check(None).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Overlap scoring and consensus base calling for the native engine.
+//!
+//! The scorers match pear's `--score_method`:
+//!
+//! 1. `Oes`, the observed expected score: +1 for a match and -1 for a
+//!    mismatch weighted by the chance, given both error probabilities,
+//!    that the true bases match
+//! 2. `AssemblyScore`: +1 for a match and -1 for a mismatch scaled by the
+//!    chance that both bases are correct
+//! 3. `Simple`: +1 for a match and -1 for a mismatch ignoring quality
+//!
+//! Qualities are numeric, without the PHRED offset.
+
+use std::fmt;
+
+// --------------------------------------------------
+/// Scores one aligned pair of called bases
+pub trait Scorer: fmt::Debug + Send + Sync {
+    fn score(&self, b1: u8, q1: u8, b2: u8, q2: u8) -> f64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Oes;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AssemblyScore;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Simple;
+
+impl Scorer for Oes {
+    fn score(&self, b1: u8, q1: u8, b2: u8, q2: u8) -> f64 {
+        let (e1, e2) = (error_prob(q1), error_prob(q2));
+        let p_match = if same_base(b1, b2) {
+            // Both right, or both wrong the same way
+            (1. - e1) * (1. - e2) + e1 * e2 / 3.
+        } else {
+            // One wrong into the other's base, or both wrong alike
+            ((1. - e1) * e2 + e1 * (1. - e2)) / 3. + 2. * e1 * e2 / 9.
+        };
+        2. * p_match - 1.
+    }
+}
+
+impl Scorer for AssemblyScore {
+    fn score(&self, b1: u8, q1: u8, b2: u8, q2: u8) -> f64 {
+        let p_correct = (1. - error_prob(q1)) * (1. - error_prob(q2));
+        if same_base(b1, b2) {
+            p_correct
+        } else {
+            -p_correct
+        }
+    }
+}
+
+impl Scorer for Simple {
+    fn score(&self, b1: u8, _q1: u8, b2: u8, _q2: u8) -> f64 {
+        if same_base(b1, b2) {
+            1.
+        } else {
+            -1.
+        }
+    }
+}
+
+/// The scorer for pear's `--score_method`, the assembly score (pear's
+/// default) unless 1 or 3
+pub fn scorer(method: u32) -> Box<dyn Scorer> {
+    match method {
+        1 => Box::new(Oes),
+        3 => Box::new(Simple),
+        _ => Box::new(AssemblyScore),
+    }
+}
+
+/// The name pear prints for a scoring method
+pub fn method_name(method: u32) -> &'static str {
+    match method {
+        1 => "OES",
+        3 => "Ignore quality",
+        _ => "Scaled score",
+    }
+}
+
+// --------------------------------------------------
+/// Calls one base from two (base, quality) observations. Matching
+/// bases sum their qualities up to `max_qual`. Of mismatching called
+/// bases the higher-quality one is kept with the difference of the
+/// qualities, or with `nbase` an "N" with the higher quality. An
+/// uncalled base defers to the other.
+pub fn consensus(
+    x: (u8, u8),
+    y: (u8, u8),
+    max_qual: u8,
+    nbase: bool,
+) -> (u8, u8) {
+    let ((b1, q1), (b2, q2)) = (x, y);
+    if is_uncalled(b1) {
+        y
+    } else if is_uncalled(b2) {
+        x
+    } else if same_base(b1, b2) {
+        (b1, q1.saturating_add(q2).min(max_qual))
+    } else if nbase {
+        (b'N', q1.max(q2))
+    } else if q1 >= q2 {
+        (b1, (q1 - q2).max(2))
+    } else {
+        (b2, (q2 - q1).max(2))
+    }
+}
+
+/// The chance a base call is wrong, 10^(-q/10)
+pub fn error_prob(qual: u8) -> f64 {
+    10f64.powf(-(qual as f64) / 10.)
+}
+
+pub fn is_uncalled(base: u8) -> bool {
+    !matches!(base, b'A' | b'C' | b'G' | b'T' | b'a' | b'c' | b'g' | b't')
+}
+
+fn same_base(b1: u8, b2: u8) -> bool {
+    b1.eq_ignore_ascii_case(&b2)
+}
+
+// --------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(x: f64, y: f64) -> bool {
+        (x - y).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_scorers() {
+        // Quality 10 is a 0.1 error probability, 40 is 0.0001
+        assert!(close(error_prob(10), 0.1));
+        assert!(close(error_prob(0), 1.));
+
+        assert_eq!(Simple.score(b'A', 2, b'a', 40), 1.);
+        assert_eq!(Simple.score(b'A', 40, b'C', 40), -1.);
+
+        assert!(close(AssemblyScore.score(b'A', 10, b'A', 10), 0.81));
+        assert!(close(AssemblyScore.score(b'A', 10, b'G', 10), -0.81));
+
+        // 2 * (0.81 + 0.01 / 3) - 1
+        assert!(close(Oes.score(b'A', 10, b'A', 10), 0.62 + 0.02 / 3.));
+        // 2 * (0.18 / 3 + 0.02 / 9) - 1
+        assert!(close(Oes.score(b'A', 10, b'G', 10), 0.12 + 0.04 / 9. - 1.));
+
+        // Confident bases score close to the simple score
+        for scorer in &[scorer(1), scorer(2), scorer(3)] {
+            assert!(scorer.score(b'C', 60, b'C', 60) > 0.99);
+            assert!(scorer.score(b'C', 60, b'T', 60) < -0.99);
+        }
+
+        // Low qualities weaken both matches and mismatches
+        assert!(Oes.score(b'C', 3, b'C', 3) < Oes.score(b'C', 30, b'C', 30));
+        assert!(Oes.score(b'C', 3, b'T', 3) > Oes.score(b'C', 30, b'T', 30));
+    }
+
+    #[test]
+    fn test_consensus() {
+        assert_eq!(consensus((b'A', 30), (b'A', 20), 40, false), (b'A', 40));
+        assert_eq!(consensus((b'A', 30), (b'A', 20), 93, false), (b'A', 50));
+        assert_eq!(consensus((b'A', 30), (b'C', 20), 40, false), (b'A', 10));
+        assert_eq!(consensus((b'A', 20), (b'C', 30), 40, false), (b'C', 10));
+        assert_eq!(consensus((b'A', 20), (b'C', 20), 40, false), (b'A', 2));
+        assert_eq!(consensus((b'N', 2), (b'C', 30), 40, false), (b'C', 30));
+
+        // --nbase only applies to two called bases
+        assert_eq!(consensus((b'A', 30), (b'C', 20), 40, true), (b'N', 30));
+        assert_eq!(consensus((b'N', 2), (b'C', 30), 40, true), (b'C', 30));
+        assert_eq!(consensus((b'A', 30), (b'A', 20), 40, true), (b'A', 40));
+    }
+}
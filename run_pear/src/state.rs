@@ -0,0 +1,329 @@
+//! The batch state file, `<out_dir>/.run_pear/state.json`.
+//!
+//! Each sample's entry records the size and modification time of its
+//! inputs, the command that merged it, the PHRED base detected for it,
+//! how the job ended and the sizes and CRC-32s of its outputs. `--resume` skips a sample only if it
+//! completed with the same inputs and command and its outputs are
+//! unchanged. A job still marked running when the file is loaded was
+//! cut short and is read back as interrupted.
+
+use crate::MyResult;
+use flate2::Crc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directory holding the state file in the output directory
+pub(crate) const STATE_DIR: &str = ".run_pear";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RunState {
+    Running,
+    Completed,
+    Failed,
+    TimedOut,
+    Interrupted,
+}
+
+/// The size and modification time of an input file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Fingerprint {
+    path: PathBuf,
+    size: u64,
+    modified: Option<u64>,
+}
+
+/// The size and CRC-32 of an output file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Checksum {
+    path: PathBuf,
+    size: u64,
+    crc32: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SampleState {
+    pub status: RunState,
+    pub command: String,
+    /// The PHRED base detected from the inputs and added to the command
+    #[serde(default)]
+    pub phred_base: Option<u32>,
+    pub inputs: Vec<Fingerprint>,
+    pub outputs: Vec<Checksum>,
+    /// Seconds since the epoch
+    pub updated: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    #[serde(skip)]
+    path: PathBuf,
+    samples: BTreeMap<String, SampleState>,
+}
+
+impl fmt::Display for RunState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RunState::Running => "running",
+            RunState::Completed => "completed",
+            RunState::Failed => "failed",
+            RunState::TimedOut => "timed_out",
+            RunState::Interrupted => "interrupted",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl State {
+    /// Reads the state of the output directory, empty if there is none
+    pub fn load(out_dir: &Path) -> MyResult<State> {
+        let path = out_dir.join(STATE_DIR).join("state.json");
+        let mut state: State = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+                .map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+        };
+
+        for sample in state.samples.values_mut() {
+            if sample.status == RunState::Running {
+                sample.status = RunState::Interrupted;
+            }
+        }
+        state.path = path;
+
+        Ok(state)
+    }
+
+    /// Writes the state through a temporary file so a crash cannot
+    /// leave it half-written
+    pub fn save(&self) -> MyResult<()> {
+        if let Some(dir) = self.path.parent() {
+            DirBuilder::new().recursive(true).create(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        serde_json::to_writer_pretty(File::create(&tmp)?, self)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, sample: &str) -> Option<&SampleState> {
+        self.samples.get(sample)
+    }
+
+    /// Records that a sample's job is about to run
+    pub fn start(
+        &mut self,
+        sample: &str,
+        command: &str,
+        inputs: &[PathBuf],
+        phred_base: Option<u32>,
+    ) -> MyResult<()> {
+        let inputs = inputs
+            .iter()
+            .map(|path| {
+                fingerprint(path).map_err(|e| {
+                    From::from(format!("{}: {}", path.display(), e))
+                })
+            })
+            .collect::<MyResult<Vec<_>>>()?;
+
+        self.samples.insert(
+            sample.to_string(),
+            SampleState {
+                status: RunState::Running,
+                command: command.to_string(),
+                phred_base,
+                inputs,
+                outputs: vec![],
+                updated: now(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Records how a sample's job ended, with checksums of the outputs
+    /// if it completed
+    pub fn finish(
+        &mut self,
+        sample: &str,
+        status: RunState,
+        outputs: &[PathBuf],
+    ) -> MyResult<()> {
+        let entry = match self.samples.get_mut(sample) {
+            Some(entry) => entry,
+            _ => return Err(From::from(format!("{}: not started", sample))),
+        };
+
+        entry.outputs = if status == RunState::Completed {
+            outputs
+                .iter()
+                .map(|path| {
+                    checksum(path).map_err(|e| {
+                        From::from(format!("{}: {}", path.display(), e))
+                    })
+                })
+                .collect::<MyResult<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+        entry.status = status;
+        entry.updated = now();
+        Ok(())
+    }
+
+    /// Why a sample must be merged again, or `None` if it completed with
+    /// this command and these inputs and its outputs are unchanged
+    pub fn rerun_reason(
+        &self,
+        sample: &str,
+        command: &str,
+        inputs: &[PathBuf],
+    ) -> Option<String> {
+        let entry = match self.samples.get(sample) {
+            Some(entry) => entry,
+            _ => return Some("no record of a previous run".to_string()),
+        };
+
+        if entry.status != RunState::Completed {
+            return Some(format!("previous run {}", entry.status));
+        }
+
+        if entry.command != command {
+            return Some("command changed".to_string());
+        }
+
+        let recorded: Vec<&PathBuf> =
+            entry.inputs.iter().map(|f| &f.path).collect();
+        if recorded != inputs.iter().collect::<Vec<_>>() {
+            return Some("inputs changed".to_string());
+        }
+
+        for input in &entry.inputs {
+            if fingerprint(&input.path).ok().as_ref() != Some(input) {
+                return Some(format!("{} changed", input.path.display()));
+            }
+        }
+
+        for output in &entry.outputs {
+            if checksum(&output.path).ok().as_ref() != Some(output) {
+                return Some(format!("{} changed", output.path.display()));
+            }
+        }
+
+        None
+    }
+}
+
+// --------------------------------------------------
+fn fingerprint(path: &Path) -> io::Result<Fingerprint> {
+    let meta = fs::metadata(path)?;
+    Ok(Fingerprint {
+        path: path.to_path_buf(),
+        size: meta.len(),
+        modified: meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+    })
+}
+
+fn checksum(path: &Path) -> io::Result<Checksum> {
+    let mut file = File::open(path)?;
+    let mut crc = Crc::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => crc.update(&buf[..n]),
+        }
+    }
+    Ok(Checksum {
+        path: path.to_path_buf(),
+        size: crc.amount() as u64,
+        crc32: format!("{:08x}", crc.sum()),
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// --------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("s1_R1.fastq");
+        let output = dir.path().join("s1.assembled.fastq");
+        fs::write(&input, "@r1\nACGT\n+\nIIII\n").unwrap();
+        fs::write(&output, "@r1\nACGT\n+\nIIII\n").unwrap();
+        let inputs = vec![input.clone()];
+
+        let mut state = State::load(dir.path()).unwrap();
+        assert_eq!(
+            state.rerun_reason("s1", "pear", &inputs),
+            Some("no record of a previous run".to_string())
+        );
+
+        // A job that never finished is interrupted when read back
+        state.start("s1", "pear", &inputs, None).unwrap();
+        state.save().unwrap();
+        let mut state = State::load(dir.path()).unwrap();
+        assert_eq!(state.get("s1").unwrap().status, RunState::Interrupted);
+        assert_eq!(
+            state.rerun_reason("s1", "pear", &inputs),
+            Some("previous run interrupted".to_string())
+        );
+
+        state.start("s1", "pear", &inputs, None).unwrap();
+        state
+            .finish("s1", RunState::Completed, std::slice::from_ref(&output))
+            .unwrap();
+        state.save().unwrap();
+        let state = State::load(dir.path()).unwrap();
+        let entry = state.get("s1").unwrap();
+        assert_eq!(entry.status, RunState::Completed);
+        assert_eq!(entry.outputs.len(), 1);
+        assert_eq!(entry.outputs[0].size, 16);
+        assert_eq!(state.rerun_reason("s1", "pear", &inputs), None);
+
+        assert_eq!(
+            state.rerun_reason("s1", "pear -v 20", &inputs),
+            Some("command changed".to_string())
+        );
+        assert_eq!(
+            state.rerun_reason("s1", "pear", &[]),
+            Some("inputs changed".to_string())
+        );
+
+        // A truncated output is caught by its checksum
+        fs::write(&output, "@r1\nACGT\n+\nII").unwrap();
+        assert_eq!(
+            state.rerun_reason("s1", "pear", &inputs),
+            Some(format!("{} changed", output.display()))
+        );
+
+        fs::remove_file(&input).unwrap();
+        assert_eq!(
+            state.rerun_reason("s1", "pear", &inputs),
+            Some(format!("{} changed", input.display()))
+        );
+
+        assert!(State::load(&dir.path().join("missing")).is_ok());
+        fs::write(dir.path().join(STATE_DIR).join("state.json"), "{").unwrap();
+        assert!(State::load(dir.path()).is_err());
+    }
+}
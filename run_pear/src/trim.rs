@@ -0,0 +1,166 @@
+//! Quality trimming with pear's `-q`/`-t` rules.
+//!
+//! A read is cut at the first of two consecutive bases whose qualities
+//! are both strictly below the threshold, and reads shorter than the
+//! minimum length afterwards are rejected. A threshold of 0 trims
+//! nothing.
+
+use crate::fastq::Record;
+
+// --------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trimmer {
+    pub quality_threshold: u8,
+    pub min_length: usize,
+    pub phred_base: u8,
+}
+
+impl Default for Trimmer {
+    /// pear's defaults
+    fn default() -> Trimmer {
+        Trimmer {
+            quality_threshold: 0,
+            min_length: 1,
+            phred_base: 33,
+        }
+    }
+}
+
+/// A record cut to its first `len` bases without copying
+#[derive(Debug, Clone, Copy)]
+pub struct Trimmed<'a, R: Record> {
+    record: &'a R,
+    len: usize,
+}
+
+impl<'a, R: Record> Trimmed<'a, R> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether any bases were cut
+    pub fn is_trimmed(&self) -> bool {
+        self.len < self.record.seq().len()
+    }
+}
+
+impl<'a, R: Record> Record for Trimmed<'a, R> {
+    fn head(&self) -> &[u8] {
+        self.record.head()
+    }
+
+    fn seq(&self) -> &[u8] {
+        &self.record.seq()[..self.len]
+    }
+
+    fn qual(&self) -> &[u8] {
+        &self.record.qual()[..self.len]
+    }
+}
+
+impl Trimmer {
+    /// Trims the record, or `None` if fewer than `min_length` bases remain
+    pub fn trim<'a, R: Record>(&self, record: &'a R) -> Option<Trimmed<'a, R>> {
+        let len = self.trim_point(record.qual());
+        if len < self.min_length {
+            None
+        } else {
+            Some(Trimmed { record, len })
+        }
+    }
+
+    /// The number of bases kept from the qualities
+    pub fn trim_point(&self, qual: &[u8]) -> usize {
+        let low =
+            |q: u8| q.saturating_sub(self.phred_base) < self.quality_threshold;
+        qual.windows(2)
+            .position(|pair| low(pair[0]) && low(pair[1]))
+            .unwrap_or(qual.len())
+    }
+}
+
+// --------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastq::OwnedRecord;
+
+    fn record(qual: &str) -> OwnedRecord {
+        OwnedRecord {
+            head: b"r1".to_vec(),
+            seq: "ACGT".repeat(4).as_bytes()[..qual.len()].to_vec(),
+            qual: qual.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_trim_point() {
+        // "5" is 20, "4" is 19 and "#" is 2 with Phred+33
+        let trimmer = Trimmer {
+            quality_threshold: 20,
+            ..Trimmer::default()
+        };
+
+        // Nothing below the threshold
+        assert_eq!(trimmer.trim_point(b"IIII5555"), 8);
+        // Equal to the threshold is not "strictly less"
+        assert_eq!(trimmer.trim_point(b"III55III"), 8);
+        // One low base alone is kept
+        assert_eq!(trimmer.trim_point(b"III4III4"), 8);
+        // Cut at the first of two consecutive low bases
+        assert_eq!(trimmer.trim_point(b"III44III"), 3);
+        assert_eq!(trimmer.trim_point(b"I4I#4III"), 3);
+        assert_eq!(trimmer.trim_point(b"##IIIIII"), 0);
+        assert_eq!(trimmer.trim_point(b""), 0);
+
+        // A threshold of 0 never trims
+        assert_eq!(Trimmer::default().trim_point(b"!!!!"), 4);
+
+        // Phred+64
+        let trimmer = Trimmer {
+            quality_threshold: 20,
+            phred_base: 64,
+            ..Trimmer::default()
+        };
+        assert_eq!(trimmer.trim_point(b"hhhSShhh"), 3);
+        assert_eq!(trimmer.trim_point(b"hhhTThhh"), 8);
+    }
+
+    #[test]
+    fn test_trim() {
+        let trimmer = Trimmer {
+            quality_threshold: 20,
+            min_length: 3,
+            ..Trimmer::default()
+        };
+
+        let rec = record("IIII##II");
+        let trimmed = trimmer.trim(&rec).unwrap();
+        assert_eq!(trimmed.len(), 4);
+        assert!(trimmed.is_trimmed());
+        assert_eq!(trimmed.head(), b"r1");
+        assert_eq!(trimmed.seq(), b"ACGT");
+        assert_eq!(trimmed.qual(), b"IIII");
+
+        let rec = record("IIIIIIII");
+        let trimmed = trimmer.trim(&rec).unwrap();
+        assert_eq!(trimmed.len(), 8);
+        assert!(!trimmed.is_trimmed());
+
+        // Exactly the minimum length is kept, shorter is rejected
+        assert_eq!(trimmer.trim(&record("III##III")).map(|t| t.len()), Some(3));
+        assert!(trimmer.trim(&record("II##IIII")).is_none());
+
+        // pear's default minimum of 1 rejects reads trimmed to nothing
+        let trimmer = Trimmer {
+            quality_threshold: 20,
+            ..Trimmer::default()
+        };
+        assert!(trimmer.trim(&record("##IIIIII")).is_none());
+        assert!(trimmer.trim(&record("I##IIIII")).is_some());
+    }
+}